@@ -0,0 +1,206 @@
+//! Measures how long updates have to wait for the async runtime while a large album is zipped.
+//!
+//! A ticker task stands in for incoming messages: every 10 ms it records how late it was woken up.
+//! The runtime has a single thread, as it would be under load with every worker busy,
+//! so any blocking work on it delays all other updates.
+//!
+//! Run with `cargo bench --bench latency`. The album size in MB can be changed with
+//! the `LATENCY_BENCH_ALBUM_MB` environment variable (256 by default).
+
+use archivegrambot::jobs::ProgressSender;
+use archivegrambot::operations::{generate_albums, ArchiveOptions};
+use archivegrambot::templates::load_templates;
+use archivegrambot::utils::{zip_files, Compression};
+use serde_json::json;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+const FILE_SIZE: usize = 8 * 1024 * 1024;
+const USER_ID: u64 = 1;
+
+fn main() {
+    let album_size_in_mb: usize = std::env::var("LATENCY_BENCH_ALBUM_MB")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(256);
+
+    let root = tempfile::tempdir().expect("Unable to create temporary folder");
+    let data_folder = root.path().join("data");
+    let result_folder = root.path().join("result");
+    create_album(&data_folder, album_size_in_mb * 1024 * 1024 / FILE_SIZE);
+    fs::create_dir_all(&result_folder).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!("Album size: {} MB", album_size_in_mb);
+    println!(
+        "{:<32} {:>10} {:>10} {:>10}",
+        "Scenario", "p50, ms", "p99, ms", "max, ms"
+    );
+
+    report(
+        "idle",
+        runtime.block_on(measure(async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        })),
+    );
+
+    let folder = data_folder.join(USER_ID.to_string());
+    let zip_file = root.path().join("blocking.zip");
+    report(
+        "zip_files on the runtime",
+        runtime.block_on(measure(async {
+            zip_files(
+                &list_files(&folder),
+                &zip_file,
+                Compression::Deflate,
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        })),
+    );
+
+    let templates = load_templates(None).unwrap();
+    let progress = ProgressSender::default();
+    report(
+        "generate_albums",
+        runtime.block_on(measure(async {
+            generate_albums(
+                "<ALL>".to_string(),
+                USER_ID,
+                &data_folder.to_string_lossy(),
+                &result_folder.to_string_lossy(),
+                &templates,
+                ArchiveOptions {
+                    compression: Compression::Deflate,
+                    max_volume_size: u64::MAX,
+                    posts_per_page: 100,
+                },
+                &progress,
+            )
+            .await
+            .unwrap();
+        })),
+    );
+}
+
+/// Runs the work while a ticker task records how late each tick was
+async fn measure(work: impl Future<Output = ()>) -> Vec<Duration> {
+    let delays = Arc::new(Mutex::new(Vec::new()));
+
+    let ticker = tokio::spawn({
+        let delays = delays.clone();
+        async move {
+            let mut interval = tokio::time::interval(TICK_INTERVAL);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                let deadline = interval.tick().await;
+                delays
+                    .lock()
+                    .unwrap()
+                    .push(Instant::now() - deadline.into_std());
+            }
+        }
+    });
+
+    // Let the ticker start before the work occupies the runtime
+    tokio::task::yield_now().await;
+    work.await;
+
+    // Give the ticker a chance to record the tick it has been waiting for
+    tokio::time::sleep(TICK_INTERVAL).await;
+    ticker.abort();
+
+    let delays = delays.lock().unwrap().clone();
+    delays
+}
+
+fn report(scenario: &str, mut delays: Vec<Duration>) {
+    delays.sort();
+    let percentile = |p: usize| {
+        delays
+            .get((delays.len() * p / 100).min(delays.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+
+    println!(
+        "{:<32} {:>10.1} {:>10.1} {:>10.1}",
+        scenario,
+        percentile(50).as_secs_f64() * 1000.0,
+        percentile(99).as_secs_f64() * 1000.0,
+        delays.last().copied().unwrap_or_default().as_secs_f64() * 1000.0
+    );
+}
+
+/// Lists files of a folder paired with their path inside the archive, as `zip_files` expects them
+fn list_files(folder: &Path) -> Vec<(PathBuf, String)> {
+    walkdir::WalkDir::new(folder)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            let name = entry
+                .path()
+                .strip_prefix(folder)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            (entry.path().to_path_buf(), name)
+        })
+        .collect()
+}
+
+/// Creates a user folder with a single album of incompressible video files
+fn create_album(data_folder: &Path, file_count: usize) {
+    let album_folder = data_folder.join(USER_ID.to_string()).join("bench");
+    fs::create_dir_all(&album_folder).unwrap();
+
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut videos = Vec::new();
+    for i in 0..file_count {
+        let contents: Vec<u8> = (0..FILE_SIZE / 8)
+            .flat_map(|_| {
+                // xorshift64 to produce data which doesn't compress
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed.to_le_bytes()
+            })
+            .collect();
+        let file_name = format!("video-{}.mp4", i);
+        fs::write(album_folder.join(&file_name), contents).unwrap();
+        videos.push(file_name);
+    }
+
+    let data = json!({
+        "channels": [{
+            "id": 1,
+            "title": "Benchmark",
+            "description": "",
+            "username": "bench",
+            "posts": [{
+                "id": 1,
+                "date": "2024-01-01 00:00:00 UTC",
+                "forward_date": "2024-01-01 00:00:00 UTC",
+                "text": "",
+                "photos": [],
+                "videos": videos,
+            }],
+        }],
+    });
+    fs::write(
+        data_folder.join(USER_ID.to_string()).join("data.json"),
+        data.to_string(),
+    )
+    .unwrap();
+}
@@ -0,0 +1,287 @@
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::operations::ArchiveError;
+use crate::utils::Config;
+
+/// Access levels, ordered from the least to the most privileged one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // Can only view and generate albums
+    ReadOnly,
+    // Can archive posts and manage own albums
+    User,
+    // Can also manage the bot and other users
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::ReadOnly => write!(f, "read-only"),
+            Role::User => write!(f, "user"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct AccessList {
+    #[serde(default)]
+    users: Vec<RuntimeUser>,
+    #[serde(default)]
+    invites: Vec<Invite>,
+    // Per-user folder size limits in MB set by admins
+    #[serde(default)]
+    quotas: HashMap<u64, u32>,
+    #[serde(default)]
+    banned: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct RuntimeUser {
+    id: u64,
+    role: Role,
+    invited_by: u64,
+    added_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Invite {
+    code: String,
+    role: Role,
+    created_by: u64,
+    // Invites without expiration time can only be used once
+    expires_at: Option<i64>,
+}
+
+pub struct NewInvite {
+    pub code: String,
+    pub role: Role,
+    pub expires_at: Option<i64>,
+}
+
+/// Users granted access at runtime with invite codes, kept next to user folders
+#[derive(Clone)]
+pub struct AccessStore {
+    file_path: PathBuf,
+    access_list: Arc<Mutex<AccessList>>,
+}
+
+impl AccessStore {
+    pub fn load(data_folder: &str) -> Result<Self, ArchiveError> {
+        let file_path = Path::new(data_folder).join("access.json");
+
+        let mut access_list: AccessList = if file_path.exists() {
+            let json_data = fs::read_to_string(&file_path)?;
+            serde_json::from_str(&json_data)?
+        } else {
+            AccessList::default()
+        };
+
+        // Ignore limits of 0 MB edited into the file by hand, as `set_quota` never stores them
+        access_list
+            .quotas
+            .retain(|_, max_user_folder_size| *max_user_folder_size > 0);
+
+        Ok(AccessStore {
+            file_path,
+            access_list: Arc::new(Mutex::new(access_list)),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AccessList> {
+        // The list is only modified together with saving it, so a poisoned lock still holds valid data
+        match self.access_list.lock() {
+            Ok(access_list) => access_list,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn save(&self, access_list: &AccessList) -> Result<(), ArchiveError> {
+        let json_data = serde_json::to_string_pretty(access_list)?;
+        fs::write(&self.file_path, json_data)?;
+        Ok(())
+    }
+
+    pub fn get_role(&self, user_id: u64) -> Option<Role> {
+        self.lock()
+            .users
+            .iter()
+            .find(|user| user.id == user_id)
+            .map(|user| user.role)
+    }
+
+    pub fn create_invite(
+        &self,
+        created_by: u64,
+        role: Role,
+        valid_for_hours: Option<u32>,
+    ) -> Result<NewInvite, ArchiveError> {
+        // Deep link parameters may only contain letters, digits, underscores and hyphens
+        let code: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let expires_at =
+            valid_for_hours.map(|hours| Utc::now().timestamp() + i64::from(hours) * 3600);
+
+        let mut access_list = self.lock();
+        let now = Utc::now().timestamp();
+        access_list
+            .invites
+            .retain(|invite| invite.expires_at.is_none_or(|expires_at| expires_at > now));
+        access_list.invites.push(Invite {
+            code: code.clone(),
+            role,
+            created_by,
+            expires_at,
+        });
+        self.save(&access_list)?;
+
+        Ok(NewInvite {
+            code,
+            role,
+            expires_at,
+        })
+    }
+
+    pub fn redeem_invite(&self, code: &str, user_id: u64) -> Result<Role, ArchiveError> {
+        let mut access_list = self.lock();
+        let now = Utc::now().timestamp();
+
+        // Banned users keep no access through invites, so leave the code for somebody else
+        if access_list.banned.contains(&user_id) {
+            return Err(ArchiveError::UserBanned);
+        }
+
+        let Some(index) = access_list.invites.iter().position(|invite| {
+            invite.code == code && invite.expires_at.is_none_or(|expires_at| expires_at > now)
+        }) else {
+            return Err(ArchiveError::InvalidInvite);
+        };
+
+        let invite = access_list.invites[index].clone();
+        if invite.expires_at.is_none() {
+            access_list.invites.remove(index);
+        }
+
+        match access_list.users.iter_mut().find(|user| user.id == user_id) {
+            // Never downgrade a user who already has a higher role
+            Some(user) => user.role = user.role.max(invite.role),
+            None => access_list.users.push(RuntimeUser {
+                id: user_id,
+                role: invite.role,
+                invited_by: invite.created_by,
+                added_at: now,
+            }),
+        }
+        self.save(&access_list)?;
+
+        Ok(invite.role)
+    }
+
+    pub fn get_quota(&self, user_id: u64) -> Option<u32> {
+        self.lock().quotas.get(&user_id).copied()
+    }
+
+    pub fn set_quota(&self, user_id: u64, max_user_folder_size: u32) -> Result<(), ArchiveError> {
+        // A limit of 0 MB would leave the user without any space
+        if max_user_folder_size == 0 {
+            return Err(ArchiveError::InvalidQuota);
+        }
+
+        let mut access_list = self.lock();
+        access_list.quotas.insert(user_id, max_user_folder_size);
+        self.save(&access_list)
+    }
+
+    pub fn is_banned(&self, user_id: u64) -> bool {
+        self.lock().banned.contains(&user_id)
+    }
+
+    pub fn ban(&self, user_id: u64) -> Result<(), ArchiveError> {
+        let mut access_list = self.lock();
+        access_list.users.retain(|user| user.id != user_id);
+        if !access_list.banned.contains(&user_id) {
+            access_list.banned.push(user_id);
+        }
+        self.save(&access_list)
+    }
+
+    pub fn unban(&self, user_id: u64) -> Result<(), ArchiveError> {
+        let mut access_list = self.lock();
+
+        let Some(index) = access_list.banned.iter().position(|id| *id == user_id) else {
+            return Err(ArchiveError::UserNotFound);
+        };
+
+        access_list.banned.remove(index);
+        self.save(&access_list)
+    }
+
+    /// Returns IDs of all users mentioned in the runtime access list
+    pub fn get_user_ids(&self) -> Vec<u64> {
+        let access_list = self.lock();
+        access_list
+            .users
+            .iter()
+            .map(|user| user.id)
+            .chain(access_list.quotas.keys().copied())
+            .chain(access_list.banned.iter().copied())
+            .collect()
+    }
+
+    pub fn revoke(&self, user_id: u64) -> Result<(), ArchiveError> {
+        let mut access_list = self.lock();
+
+        let Some(index) = access_list.users.iter().position(|user| user.id == user_id) else {
+            return Err(ArchiveError::UserNotFound);
+        };
+
+        access_list.users.remove(index);
+        self.save(&access_list)?;
+
+        Ok(())
+    }
+}
+
+pub fn get_role(config: &Config, access_store: &AccessStore, user_id: u64) -> Option<Role> {
+    if access_store.is_banned(user_id) {
+        return None;
+    }
+
+    let config_role = if config.admins.contains(&user_id) {
+        Some(Role::Admin)
+    } else if config.allowed_users.contains(&user_id) {
+        Some(Role::User)
+    } else if config.read_only_users.contains(&user_id) {
+        Some(Role::ReadOnly)
+    } else {
+        None
+    };
+
+    match config_role.max(access_store.get_role(user_id)) {
+        Some(role) => Some(role),
+        None if config.restrict_access => None,
+        None => Some(Role::User),
+    }
+}
+
+/// Returns the folder size limit in MB for the user. Limits set with `/setquota` take
+/// precedence over `user_quotas` from the config, which take precedence over the global limit
+pub fn get_user_quota(config: &Config, access_store: &AccessStore, user_id: u64) -> u32 {
+    access_store
+        .get_quota(user_id)
+        .or_else(|| config.user_quotas.get(&user_id.to_string()).copied())
+        .unwrap_or(config.max_user_folder_size)
+}
@@ -0,0 +1,435 @@
+use hmac::{Hmac, Mac};
+use hyper::body::Bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log2::*;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+
+use crate::utils::{Config, SharedConfig};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of chunks in which archives are read from disk and sent to the client
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn get_signature(secret: &str, user_id: u64, file_name: &str, expires: u64) -> HmacSha256 {
+    // HMAC accepts keys of any length, so creating it can't fail
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC key of any length is valid");
+    mac.update(format!("{}/{}/{}", user_id, file_name, expires).as_bytes());
+    mac
+}
+
+/// Returns the signature of a download link as a hex string
+fn sign(secret: &str, user_id: u64, file_name: &str, expires: u64) -> String {
+    get_signature(secret, user_id, file_name, expires)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn is_valid_file_name(file_name: &str) -> bool {
+    !file_name.is_empty()
+        && !file_name.starts_with('.')
+        && file_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Returns a link to an archive kept in the user's folder inside `result_folder`, valid for
+/// `download_link_lifetime` hours. The archive is deleted by `remove_expired_archives` once the link expires
+pub fn create_download_link(config: &Config, user_id: u64, file_path: &Path) -> io::Result<String> {
+    let address = config
+        .download_server_address
+        .ok_or_else(|| io::Error::other("download server is disabled"))?;
+    let file_name = file_path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .filter(|file_name| is_valid_file_name(file_name))
+        .ok_or_else(|| io::Error::other("archive name can't be used in a link"))?;
+
+    // The link lifetime starts now, so the archive has to be kept for as long
+    let now = SystemTime::now();
+    fs::File::options()
+        .write(true)
+        .open(file_path)?
+        .set_modified(now)?;
+    let expires = unix_time(now) + config.download_link_lifetime * 60 * 60;
+
+    let signature = sign(&config.download_secret, user_id, file_name, expires);
+    let base_url = config
+        .download_base_url
+        .clone()
+        .unwrap_or_else(|| format!("http://{}", address));
+
+    Ok(format!(
+        "{}/download/{}/{}?expires={}&signature={}",
+        base_url.trim_end_matches('/'),
+        user_id,
+        file_name,
+        expires,
+        signature
+    ))
+}
+
+fn verify_signature(
+    secret: &str,
+    user_id: u64,
+    file_name: &str,
+    expires: u64,
+    signature: &str,
+) -> bool {
+    if !signature.len().is_multiple_of(2) || !signature.is_ascii() {
+        return false;
+    }
+    let signature: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).ok())
+        .collect();
+
+    signature.is_some_and(|signature| {
+        get_signature(secret, user_id, file_name, expires)
+            .verify_slice(&signature)
+            .is_ok()
+    })
+}
+
+/// Serves archives from `result_folder` to holders of a valid download link until the bot stops
+pub async fn serve(shared_config: SharedConfig, address: SocketAddr) {
+    let make_service = make_service_fn(move |_| {
+        let shared_config = shared_config.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                // Like bot updates, each request uses a snapshot of the current config
+                let config = shared_config.get();
+                async move { Ok::<_, Infallible>(handle_request(&config, request).await) }
+            }))
+        }
+    });
+
+    match Server::try_bind(&address) {
+        Ok(server) => {
+            info!("Download server is listening on {}", address);
+            if let Err(e) = server.serve(make_service).await {
+                error!("Download server stopped: {}", e);
+            }
+        }
+        Err(e) => {
+            error!("Unable to start download server on {}: {}", address, e);
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(
+        status.canonical_reason().unwrap_or_default().to_string(),
+    ));
+    *response.status_mut() = status;
+    response
+}
+
+async fn handle_request(config: &Config, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    // Links have the form `/download/<user ID>/<file name>?expires=<time>&signature=<HMAC>`
+    let mut segments = request.uri().path().trim_start_matches('/').split('/');
+    let (Some("download"), Some(user_id), Some(file_name), None) = (
+        segments.next(),
+        segments
+            .next()
+            .and_then(|user_id| user_id.parse::<u64>().ok()),
+        segments
+            .next()
+            .filter(|file_name| is_valid_file_name(file_name)),
+        segments.next(),
+    ) else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+
+    let query: HashMap<&str, &str> = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|parameter| parameter.split_once('='))
+        .collect();
+    let (Some(expires), Some(signature)) = (
+        query
+            .get("expires")
+            .and_then(|expires| expires.parse::<u64>().ok()),
+        query.get("signature"),
+    ) else {
+        return status_response(StatusCode::FORBIDDEN);
+    };
+
+    if !verify_signature(
+        &config.download_secret,
+        user_id,
+        file_name,
+        expires,
+        signature,
+    ) {
+        warn!(
+            "Download of \"{}\" of user #{} with an invalid signature refused",
+            file_name, user_id
+        );
+        return status_response(StatusCode::FORBIDDEN);
+    }
+
+    if expires < unix_time(SystemTime::now()) {
+        return status_response(StatusCode::GONE);
+    }
+
+    let file_path = Path::new(&config.result_folder)
+        .join(user_id.to_string())
+        .join(file_name);
+    let mut file = match tokio::fs::File::open(&file_path).await {
+        Ok(file) => file,
+        Err(_) => return status_response(StatusCode::NOT_FOUND),
+    };
+    let length = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    info!(
+        "Sending \"{}\" to a download link of user #{}",
+        file_name, user_id
+    );
+
+    // Stream the archive in chunks, so that large archives don't have to fit into memory
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    // The client has disconnected
+                    if sender
+                        .send_data(Bytes::copy_from_slice(&buffer[..n]))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Unable to read \"{}\": {}", file_path.display(), e);
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(header::CONTENT_LENGTH, length)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .body(body)
+        .unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Deletes archives kept in user folders of `result_folder` once their download links have expired.
+/// Returns the number of deleted archives
+pub fn remove_expired_archives(
+    result_folder: &str,
+    link_lifetime_in_hours: u64,
+) -> io::Result<usize> {
+    let lifetime = Duration::from_secs(link_lifetime_in_hours * 60 * 60);
+    let mut removed = 0;
+
+    for entry in fs::read_dir(result_folder)? {
+        let entry = entry?;
+        // Only user folders hold kept archives, job workspaces are cleaned up by their jobs
+        let is_user_folder = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.parse::<u64>().is_ok());
+        if !is_user_folder || !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        for file in fs::read_dir(entry.path())? {
+            let file = file?;
+            let expired = file
+                .metadata()?
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age > lifetime);
+            if expired && file.file_type()?.is_file() {
+                fs::remove_file(file.path())?;
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SECRET: &str = "0123456789abcdef";
+    const USER_ID: u64 = 42;
+
+    fn test_config(result_folder: &Path) -> Config {
+        toml::from_str(&format!(
+            r#"
+            teloxide_token = "token"
+            data_folder = "data"
+            max_user_folder_size = 500
+            result_folder = "{}"
+            log_path = "log.txt"
+            restrict_access = false
+            allowed_users = []
+            download_server_address = "127.0.0.1:8080"
+            download_secret = "{}"
+            "#,
+            result_folder.display(),
+            SECRET
+        ))
+        .unwrap()
+    }
+
+    /// Creates a result folder holding an archive and another file of the same user
+    fn create_result_folder() -> TempDir {
+        let result_folder = TempDir::new().unwrap();
+        let user_folder = result_folder.path().join(USER_ID.to_string());
+        fs::create_dir_all(&user_folder).unwrap();
+        fs::write(user_folder.join("archive.zip"), b"archive").unwrap();
+        fs::write(user_folder.join("other.zip"), b"other").unwrap();
+        fs::write(result_folder.path().join("secret.zip"), b"secret").unwrap();
+        result_folder
+    }
+
+    async fn get(config: &Config, path_and_query: &str) -> Response<Body> {
+        let request = Request::get(path_and_query).body(Body::empty()).unwrap();
+        handle_request(config, request).await
+    }
+
+    /// Returns the path and query of a link, which the server sees in requests
+    fn get_path_and_query(link: &str) -> &str {
+        let start = link.find("/download/").unwrap();
+        &link[start..]
+    }
+
+    fn create_link(config: &Config) -> String {
+        let file_path = Path::new(&config.result_folder)
+            .join(USER_ID.to_string())
+            .join("archive.zip");
+        create_download_link(config, USER_ID, &file_path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_link_downloads_archive() {
+        let result_folder = create_result_folder();
+        let config = test_config(result_folder.path());
+        let link = create_link(&config);
+
+        let response = get(&config, get_path_and_query(&link)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"archive");
+    }
+
+    #[tokio::test]
+    async fn tampered_signature_is_refused() {
+        let result_folder = create_result_folder();
+        let config = test_config(result_folder.path());
+        let link = create_link(&config);
+
+        // Change the last digit of the signature
+        let mut tampered = get_path_and_query(&link).to_string();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == '0' { '1' } else { '0' });
+
+        let response = get(&config, &tampered).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn tampered_file_name_is_refused() {
+        let result_folder = create_result_folder();
+        let config = test_config(result_folder.path());
+        let link = create_link(&config);
+
+        let tampered = get_path_and_query(&link).replace("archive.zip", "other.zip");
+        let response = get(&config, &tampered).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn expired_link_is_refused() {
+        let result_folder = create_result_folder();
+        let config = test_config(result_folder.path());
+
+        let expires = unix_time(SystemTime::now()) - 60;
+        let signature = sign(SECRET, USER_ID, "archive.zip", expires);
+        let response = get(
+            &config,
+            &format!(
+                "/download/{}/archive.zip?expires={}&signature={}",
+                USER_ID, expires, signature
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn path_traversal_is_refused() {
+        let result_folder = create_result_folder();
+        let config = test_config(result_folder.path());
+
+        // Even a correctly signed name must not lead out of the user folder
+        let expires = unix_time(SystemTime::now()) + 60;
+        for file_name in ["..%2Fsecret.zip", "..", "%2E%2E%2Fsecret.zip"] {
+            let signature = sign(SECRET, USER_ID, file_name, expires);
+            let response = get(
+                &config,
+                &format!(
+                    "/download/{}/{}?expires={}&signature={}",
+                    USER_ID, file_name, expires, signature
+                ),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", file_name);
+        }
+
+        let signature = sign(SECRET, USER_ID, "secret.zip", expires);
+        let response = get(
+            &config,
+            &format!(
+                "/download/{}/../secret.zip?expires={}&signature={}",
+                USER_ID, expires, signature
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,18 +1,30 @@
+use chrono::DateTime;
 use log2::*;
 use prettytable::{row, Table};
-use regex::Regex;
 use std::fs;
-use std::path::{Path, PathBuf};
-use teloxide::{prelude::*, types::InputFile, types::ParseMode, utils::command::BotCommands};
+use std::path::PathBuf;
+use teloxide::{
+    prelude::*,
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaDocument,
+        MessageId, ParseMode,
+    },
+    utils::command::BotCommands,
+};
 type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
+use crate::access::{get_role, get_user_quota, AccessStore, NewInvite, Role};
+use crate::download::create_download_link;
+use crate::jobs::{JobProgress, JobQueue, ProgressSender};
 use crate::operations::{
-    add_new_post, consolidate_media, delete_user_album, delete_user_folders, generate_albums,
-    get_album_descriptions, ChannelInfo,
-};
-use crate::utils::{
-    convert_to_mb, delete_contents_of_folder, get_folder_size, truncate_string, Config,
+    add_new_post, consolidate_media, delete_user_album, delete_user_folders, find_similar_photos,
+    generate_albums, get_album_descriptions, get_album_usage, get_themes, get_user_folder_ids,
+    set_theme, AlbumUsage, ArchiveError, ArchiveOptions, ChannelInfo, DuplicatePolicy, FileType,
+    GeneratedArchive, MediaLocation, PhotoLocation, StorageLimits, StorageUsage,
 };
+use crate::templates::{load_templates, TemplateStore, Templates, DEFAULT_THEME};
+use crate::usage::UsageStore;
+use crate::utils::{convert_to_mb, get_bot_api_limits, truncate_string, Compression, Config};
 
 #[derive(BotCommands, Clone)]
 #[command(
@@ -28,41 +40,183 @@ pub enum Command {
         description = "if the original posts contain multiple media files, consolidate them. Action is performed for all albums"
     )]
     ConsolidateAll,
-    #[command(description = "generate all albums.")]
-    GenerateAll,
     #[command(
-        description = "generate specified album (add album `username` after `generate` command)."
+        description = "generate all albums (optionally add `stored`, `deflate` or `zstd` to choose archive compression)."
+    )]
+    GenerateAll(String),
+    #[command(
+        description = "generate specified album (add album `username` and optionally compression method after `generate` command)."
     )]
     Generate(String),
+    #[command(description = "show running and queued album generations.")]
+    Jobs,
+    #[command(description = "cancel album generation (add job ID after `cancel` command).")]
+    Cancel(String),
+    #[command(
+        description = "list near-identical photos across all albums (add album `username` after `duplicates` command to check only one album)."
+    )]
+    Duplicates(String),
     #[command(description = "delete all albums.")]
     DeleteAll,
     #[command(
         description = "delete specified album (add album `username` after `delete` command)."
     )]
     Delete(String),
+    #[command(
+        description = "choose the theme of all albums (add theme name after `theme` command) or of one album (add theme name and album `username`). Use `default` to reset, or no arguments to list themes."
+    )]
+    Theme(String),
+    #[command(
+        description = "create an invite code (add `readonly` to grant read-only access and/or the number of hours the code stays valid, otherwise the code can be used once)."
+    )]
+    Invite(String),
+    #[command(
+        description = "revoke access granted with an invite code (add user ID after `revoke` command)."
+    )]
+    Revoke(String),
+    #[command(description = "list all users with their roles and occupied space.")]
+    Users,
+    #[command(
+        description = "show occupied space per album and media type (admins can add user ID after `usage` command)."
+    )]
+    Usage(String),
+    #[command(
+        description = "set folder size limit for a user (add user ID and size in MB after `setquota` command)."
+    )]
+    SetQuota(String),
+    #[command(description = "delete all data of a user (add user ID after `purge` command).")]
+    Purge(String),
+    #[command(description = "block a user from using the bot (add user ID after `ban` command).")]
+    Ban(String),
+    #[command(description = "unblock a banned user (add user ID after `unban` command).")]
+    Unban(String),
+    #[command(description = "reload album templates from the template folder.")]
+    ReloadTemplates,
 }
 
-pub async fn help(bot: Bot, msg: Message) -> HandlerResult {
-    bot.send_message(msg.chat.id, Command::descriptions().to_string())
-        .await?;
+impl Command {
+    pub fn required_role(&self) -> Role {
+        match self {
+            Command::Help
+            | Command::ShowAlbums
+            | Command::GenerateAll(_)
+            | Command::Generate(_)
+            | Command::Jobs
+            | Command::Cancel(_) => Role::ReadOnly,
+            // Everyone can see their own usage, only admins can see usage of other users
+            Command::Usage(user) if user.trim().is_empty() => Role::ReadOnly,
+            Command::ConsolidateAll
+            | Command::Duplicates(_)
+            | Command::DeleteAll
+            | Command::Delete(_)
+            | Command::Theme(_) => Role::User,
+            Command::Invite(_)
+            | Command::Revoke(_)
+            | Command::Users
+            | Command::Usage(_)
+            | Command::SetQuota(_)
+            | Command::Purge(_)
+            | Command::Ban(_)
+            | Command::Unban(_)
+            | Command::ReloadTemplates => Role::Admin,
+        }
+    }
+}
+
+/// Returns the message explaining an error caused by the user's request or data,
+/// or `None` for internal failures which are reported as a generic error
+fn user_message(err: &ArchiveError) -> Option<String> {
+    match err {
+        ArchiveError::Duplicate(original) => Some(format!(
+            "Media already exists in album \"{}\" (post #{})!",
+            original.album_title, original.post_id
+        )),
+        ArchiveError::PostAlreadyExists => Some("Post already exists!".to_string()),
+        ArchiveError::QuotaExceeded { limit_in_mb } => Some(format!(
+            "User folder cannot exceed {} MB size limit!",
+            limit_in_mb
+        )),
+        ArchiveError::StorageFull { .. } => Some(
+            "Bot storage is full, new posts cannot be archived. Please contact the bot admin!"
+                .to_string(),
+        ),
+        ArchiveError::DiskSpaceLow => Some(
+            "Not enough free disk space on the server. Please contact the bot admin!".to_string(),
+        ),
+        ArchiveError::FileTooLarge {
+            file_type,
+            limit_in_mb,
+        } => Some(format!(
+            "{} file size exceeds {} MB size limit!",
+            match file_type {
+                FileType::Photo => "Photo",
+                FileType::Video => "Video",
+            },
+            limit_in_mb
+        )),
+        ArchiveError::UsernameNotSpecified => Some("Album username is not specified!".to_string()),
+        ArchiveError::AlbumNotFound => Some("Album not found!".to_string()),
+        ArchiveError::NoData => Some("No data found!".to_string()),
+        ArchiveError::NoAlbums => Some("No albums found!".to_string()),
+        ArchiveError::InvalidInvite => Some("Invite code is invalid or has expired!".to_string()),
+        ArchiveError::UserNotFound => Some("User not found!".to_string()),
+        ArchiveError::InvalidQuota => {
+            Some("Folder size limit must be greater than 0 MB!".to_string())
+        }
+        ArchiveError::UserBanned => {
+            Some("Your access to the bot has been blocked by the bot admin!".to_string())
+        }
+        ArchiveError::NoAlbumsGenerated
+        | ArchiveError::Io(_)
+        | ArchiveError::Json(_)
+        | ArchiveError::Template(_)
+        | ArchiveError::Zip(_)
+        | ArchiveError::Telegram(_)
+        | ArchiveError::Download(_) => None,
+    }
+}
+
+pub async fn help(bot: Bot, msg: Message, role: Role) -> HandlerResult {
+    // Commands without arguments parse to themselves, and commands with a single
+    // string argument parse with an empty one, so each entry maps to its variant
+    let commands: Vec<String> = Command::bot_commands()
+        .into_iter()
+        .filter(|command| {
+            Command::parse(&command.command, "").is_ok_and(|parsed| role >= parsed.required_role())
+        })
+        .map(|command| format!("{} — {}", command.command, command.description))
+        .collect();
+
+    bot.send_message(
+        msg.chat.id,
+        format!("These commands are supported:\n\n{}", commands.join("\n")),
+    )
+    .await?;
     Ok(())
 }
 
-pub async fn showalbums(bot: Bot, msg: Message, config: &Config) -> HandlerResult {
+pub async fn showalbums(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    access_store: &AccessStore,
+    usage_store: &UsageStore,
+) -> HandlerResult {
     let user_id = msg.chat.id.0 as u64;
     let mut albums: Option<Vec<ChannelInfo>> = None;
+    let mut error: Option<ArchiveError> = None;
     match get_album_descriptions(user_id, &config.data_folder).await {
         Ok(a) => {
             albums = Some(a);
         }
         Err(err) => {
             error!("showalbums(): user #{}: {}", user_id, err);
+            error = Some(err);
         }
     }
 
     if let Some(albums) = albums {
-        let user_folder = Path::new(&config.data_folder).join(user_id.to_string());
-        let user_folder_size_in_mb = convert_to_mb(get_folder_size(&user_folder));
+        let user_folder_size_in_mb = convert_to_mb(usage_store.get_user_folder_size(user_id).await);
 
         // Create a table
         let mut table = Table::new();
@@ -83,21 +237,36 @@ pub async fn showalbums(bot: Bot, msg: Message, config: &Config) -> HandlerResul
             format!(
                 "<pre>{}
 Total occupied space: {}/{} MB</pre>",
-                table, user_folder_size_in_mb, config.max_user_folder_size
+                table,
+                user_folder_size_in_mb,
+                get_user_quota(config, access_store, user_id)
             ),
         )
         .parse_mode(ParseMode::Html)
         .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
+            .await?;
     } else {
-        bot.send_message(msg.chat.id, "❗ No albums found!").await?;
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error reading albums. Please contact bot owners!",
+        )
+        .await?;
     }
 
     Ok(())
 }
 
-pub async fn consolidateall(bot: Bot, msg: Message, config: &Config) -> HandlerResult {
+pub async fn consolidateall(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    usage_store: &UsageStore,
+) -> HandlerResult {
     let user_id = msg.chat.id.0 as u64;
     let mut ok_string: Option<String> = None;
+    let mut error: Option<ArchiveError> = None;
 
     match consolidate_media(user_id, &config.data_folder).await {
         Ok(res) => {
@@ -105,144 +274,534 @@ pub async fn consolidateall(bot: Bot, msg: Message, config: &Config) -> HandlerR
         }
         Err(err) => {
             error!("consolidateall(): user #{}: {}", user_id, err);
+            error = Some(err);
         }
     }
+    refresh_usage(usage_store, user_id).await;
 
     if let Some(message) = ok_string {
         bot.send_message(msg.chat.id, format!("✅ {}", message))
             .await?;
-    } else {
-        bot.send_message(msg.chat.id, "❗ No albums found!".to_string())
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
             .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error consolidating albums. Please contact bot owners!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn generateall(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    job_queue: &JobQueue,
+    template_store: &TemplateStore,
+    args: String,
+) -> HandlerResult {
+    let mut args = args.split_whitespace();
+    let Some(compression) = parse_compression(args.next(), args.next(), config) else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Invalid arguments! Use `/generateall [stored|deflate|zstd]`.",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    queue_generation(
+        bot,
+        msg,
+        config,
+        job_queue,
+        template_store,
+        "<ALL>".to_string(),
+        compression,
+    )
+    .await
+}
+
+pub async fn generate(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    job_queue: &JobQueue,
+    template_store: &TemplateStore,
+    args: String,
+) -> HandlerResult {
+    let mut args = args.split_whitespace();
+
+    // Check if the username is not empty
+    let Some(username) = args.next() else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Specify album username to be generated!".to_string(),
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let Some(compression) = parse_compression(args.next(), args.next(), config) else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Invalid arguments! Use `/generate <username> [stored|deflate|zstd]`.",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    queue_generation(
+        bot,
+        msg,
+        config,
+        job_queue,
+        template_store,
+        username.to_string(),
+        compression,
+    )
+    .await
+}
+
+/// Returns the compression method given as the last argument, or the configured one if it is omitted.
+/// Returns `None` for an unknown method or extra arguments
+fn parse_compression(
+    arg: Option<&str>,
+    extra: Option<&str>,
+    config: &Config,
+) -> Option<Compression> {
+    match (arg, extra) {
+        (None, _) => Some(config.archive_compression),
+        (Some(arg), None) => arg.parse().ok(),
+        _ => None,
     }
+}
+
+async fn queue_generation(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    job_queue: &JobQueue,
+    template_store: &TemplateStore,
+    username: String,
+    compression: Compression,
+) -> HandlerResult {
+    // Assume that user ID is the same as chat ID
+    let user_id = msg.chat.id.0 as u64;
+    let description = if username == "<ALL>" {
+        "all albums".to_string()
+    } else {
+        format!("album \"{}\"", username)
+    };
+
+    // The status message is edited with the job progress and deleted once the job is done
+    let status_msg = bot.send_message(msg.chat.id, "⌛️").await?;
+    let mut config = config.clone();
+    // The download server is only started or stopped on restart, so links always go to the one running now
+    config.download_server_address = job_queue.download_server_address();
+    // Like the config, templates are taken as they are when the job is queued
+    let templates = template_store.get();
+    let job_bot = bot.clone();
+    let job_id = job_queue.spawn(
+        bot,
+        status_msg,
+        user_id,
+        description,
+        |progress| async move {
+            if let Err(err) = generation_job(
+                job_bot,
+                msg,
+                &config,
+                &templates,
+                username,
+                compression,
+                progress,
+            )
+            .await
+            {
+                error!("generation_job(): user #{}: {}", user_id, err);
+            }
+        },
+    );
+    info!("Generation job #{} queued for user #{}", job_id, user_id);
 
     Ok(())
 }
 
-pub async fn generateall(bot: Bot, msg: Message, config: &Config) -> HandlerResult {
-    let mut counter: Option<u64> = None;
-    let mut zip_file: Option<PathBuf> = None;
+async fn generation_job(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    templates: &Templates,
+    username: String,
+    compression: Compression,
+    progress: ProgressSender,
+) -> HandlerResult {
+    let mut archive: Option<GeneratedArchive> = None;
+    let mut error: Option<ArchiveError> = None;
 
     // Assume that user ID is the same as chat ID
     let user_id = msg.chat.id.0 as u64;
+    let all_albums = username == "<ALL>";
+    let max_upload_size_in_mb = get_bot_api_limits(config).max_upload_size_in_mb;
 
-    // Generate all albums
     match generate_albums(
-        "<ALL>".to_string(),
+        username.clone(),
         user_id,
         &config.data_folder,
         &config.result_folder,
+        templates,
+        ArchiveOptions {
+            compression,
+            // Archives too large to be sent are downloaded from the download server instead of being split
+            max_volume_size: if config.download_server_address.is_some() {
+                u64::MAX
+            } else {
+                max_upload_size_in_mb as u64 * 1024 * 1024
+            },
+            posts_per_page: config.posts_per_page,
+        },
+        &progress,
     )
     .await
     {
-        Ok(c) => {
-            counter = Some(c.0);
-            zip_file = Some(c.1);
+        Ok(a) => {
+            archive = Some(a);
         }
         Err(err) => {
-            error!("generateall(): user #{}: {}", user_id, err);
+            error!("generation_job(): user #{}: {}", user_id, err);
+            error = Some(err);
         }
     }
 
-    if let Some(counter) = counter {
+    // The job workspace is deleted when the archive goes out of scope, whether it has been sent or not
+    if let Some(archive) = archive {
         let success_msg = bot
             .send_message(
                 msg.chat.id,
-                format!("✅ Successfully generated {} albums.", counter),
+                if all_albums {
+                    format!("✅ Successfully generated {} albums.", archive.album_count)
+                } else {
+                    format!("✅ Successfully generated album \"{}\".", username)
+                },
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+        // Volumes only exceed the upload limit if a single media file doesn't fit into one
+        let mut volume_too_large = false;
+        for volume in &archive.volumes {
+            if fs::metadata(volume)?.len() > max_upload_size_in_mb as u64 * 1024 * 1024 {
+                volume_too_large = true;
+            }
+        }
+
+        if volume_too_large && config.download_server_address.is_some() {
+            let links = archive
+                .keep(&config.result_folder, user_id)
+                .and_then(|kept_paths| {
+                    kept_paths
+                        .iter()
+                        .map(|kept_path| Ok(create_download_link(config, user_id, kept_path)?))
+                        .collect::<Result<Vec<String>, ArchiveError>>()
+                });
+            match links {
+                Ok(links) => {
+                    info!(
+                        "Download links to an archive with {} created for user #{}",
+                        if all_albums {
+                            "all albums".to_string()
+                        } else {
+                            format!("album \"{}\"", username)
+                        },
+                        user_id
+                    );
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "📦 Archive exceeds {} MB and can be downloaded within {} hours:\n{}",
+                            max_upload_size_in_mb,
+                            config.download_link_lifetime,
+                            links.join("\n")
+                        ),
+                    )
+                    .reply_to_message_id(success_msg.id)
+                    .await?;
+                }
+                Err(err) => {
+                    error!("generation_job(): user #{}: {}", user_id, err);
+                    bot.send_message(
+                        msg.chat.id,
+                        "❌ Error creating download links. Please contact bot owners!",
+                    )
+                    .reply_to_message_id(success_msg.id)
+                    .await?;
+                }
+            }
+        } else if volume_too_large {
+            let kept_paths = archive.keep(&config.result_folder, user_id)?;
+            warn!(
+                "An archive with {} requested by user #{} exceeds {} MB size limit \
+                 and hasn't been sent, it is kept at {:?}",
+                if all_albums {
+                    "all albums".to_string()
+                } else {
+                    format!("album \"{}\"", username)
+                },
+                user_id,
+                max_upload_size_in_mb,
+                kept_paths
+            );
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "❗ Archive contains a file exceeding {} MB and cannot be sent automatically. \
+                     In order to get it, please contact bot owners.",
+                    max_upload_size_in_mb
+                ),
             )
+            .reply_to_message_id(success_msg.id)
             .await?;
-        let zip_path = zip_file.unwrap();
-        // Do not try to send an archive that exceed 20 MB
-        if fs::metadata(&zip_path).unwrap().len() > 20 * 1024 * 1024 {
-            warn!("An archive with all albums requested by user #{} exceeds 20 MB size limit and hasn't been sent", user_id);
-            bot.send_message(msg.chat.id, "❗ Archive size exceeds 20 MB and cannot be sent automatically. In order to get it, please contact bot owners.").reply_to_message_id(success_msg.id).await?;
         } else {
-            let waiting_msg = bot.send_message(msg.chat.id, "⌛️").await?;
-            let input_file = InputFile::file(&zip_path);
-            bot.send_document(msg.chat.id, input_file)
-                .reply_to_message_id(success_msg.id)
-                .await?;
-            bot.delete_message(msg.chat.id, waiting_msg.id).await?;
-            info!("Sent an archive with all albums to user #{}", user_id);
-            delete_contents_of_folder(&config.result_folder).await?;
+            progress.send_replace(JobProgress::Sending);
+            send_archive(&bot, msg.chat.id, success_msg.id, &archive).await?;
+            if all_albums {
+                info!("Sent an archive with all albums to user #{}", user_id);
+            } else {
+                info!(
+                    "Sent an archive with album \"{}\" to user #{}",
+                    username, user_id
+                );
+            }
         }
-    } else {
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
+            .reply_to_message_id(msg.id)
+            .await?;
+    } else if all_albums {
         bot.send_message(msg.chat.id, "❗ Error generating albums!")
+            .reply_to_message_id(msg.id)
             .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            format!("❌ Error generating album \"{}\"!", username),
+        )
+        .reply_to_message_id(msg.id)
+        .await?;
     }
 
     Ok(())
 }
 
-pub async fn generate(bot: Bot, msg: Message, config: &Config, username: String) -> HandlerResult {
-    // Check if the username is not empty
-    if username.is_empty() {
+/// Sends a single archive as a document, or the index and all volumes of a split archive as document groups
+async fn send_archive(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    archive: &GeneratedArchive,
+) -> HandlerResult {
+    // Telegram accepts between 2 and 10 documents in a group
+    const MAX_GROUP_SIZE: usize = 10;
+
+    let files: Vec<&PathBuf> = archive
+        .index_path
+        .iter()
+        .chain(archive.volumes.iter())
+        .collect();
+
+    for group in files.chunks(MAX_GROUP_SIZE) {
+        if let [file] = group {
+            bot.send_document(chat_id, InputFile::file(file.as_path()))
+                .reply_to_message_id(reply_to)
+                .await?;
+        } else {
+            let documents = group.iter().map(|file| {
+                InputMedia::Document(InputMediaDocument::new(InputFile::file(file.as_path())))
+            });
+            bot.send_media_group(chat_id, documents)
+                .reply_to_message_id(reply_to)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn jobs(bot: Bot, msg: Message, job_queue: &JobQueue, role: Role) -> HandlerResult {
+    // Admins see jobs of all users
+    let user_id = msg.chat.id.0 as u64;
+    let jobs = job_queue.list((role < Role::Admin).then_some(user_id));
+
+    if jobs.is_empty() {
+        bot.send_message(msg.chat.id, "❗ No running jobs.").await?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row!["Job", "User", "Task", "Status"]);
+    jobs.iter().for_each(|job| {
+        table.add_row(row![
+            job.id,
+            job.user_id,
+            truncate_string(&job.description, 20),
+            job.progress
+        ]);
+    });
+
+    bot.send_message(msg.chat.id, format!("<pre>{}</pre>", table))
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn cancel(
+    bot: Bot,
+    msg: Message,
+    job_queue: &JobQueue,
+    role: Role,
+    job: String,
+) -> HandlerResult {
+    let user_id = msg.chat.id.0 as u64;
+    let owner = (role < Role::Admin).then_some(user_id);
+
+    // Without a job ID, cancel the only job of the user
+    let job_id = if job.trim().is_empty() {
+        match job_queue.list(Some(user_id)).as_slice() {
+            [job] => Some(job.id),
+            _ => None,
+        }
+    } else {
+        job.trim().trim_start_matches('#').parse::<u64>().ok()
+    };
+
+    let Some(job_id) = job_id else {
         bot.send_message(
             msg.chat.id,
-            "❌ Specify album username to be generated!".to_string(),
+            "❌ Specify job ID to be cancelled, see /jobs for the list of jobs!",
         )
         .await?;
         return Ok(());
+    };
+
+    if let Some(status_msg) = job_queue.cancel(job_id, owner) {
+        bot.edit_message_text(
+            status_msg.chat.id,
+            status_msg.id,
+            format!("🚫 Job #{} cancelled.", job_id),
+        )
+        .await?;
+        if status_msg.chat.id != msg.chat.id {
+            bot.send_message(msg.chat.id, format!("✅ Job #{} cancelled.", job_id))
+                .await?;
+        }
+    } else {
+        bot.send_message(msg.chat.id, format!("❗ Job #{} not found!", job_id))
+            .await?;
     }
 
-    let mut counter: Option<u64> = None;
-    let mut zip_file: Option<PathBuf> = None;
-    let mut error_string = String::new();
+    Ok(())
+}
 
-    // Assume that user ID is the same as chat ID
+pub async fn duplicates(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    username: String,
+) -> HandlerResult {
     let user_id = msg.chat.id.0 as u64;
+    let mut groups: Option<Vec<Vec<PhotoLocation>>> = None;
+    let mut error: Option<ArchiveError> = None;
 
-    // Generate single album
-    match generate_albums(
-        username.clone(),
+    let waiting_msg = bot.send_message(msg.chat.id, "⌛️").await?;
+
+    match find_similar_photos(
+        username,
         user_id,
         &config.data_folder,
-        &config.result_folder,
+        config.similarity_threshold,
     )
     .await
     {
-        Ok(c) => {
-            counter = Some(c.0);
-            zip_file = Some(c.1);
+        Ok(g) => {
+            groups = Some(g);
         }
         Err(err) => {
-            error!("generate(): user #{}: {}", user_id, err);
-            error_string = err.to_string();
+            error!("duplicates(): user #{}: {}", user_id, err);
+            error = Some(err);
         }
     }
 
-    if counter.is_some() {
-        let success_msg = bot
-            .send_message(
-                msg.chat.id,
-                format!("✅ Successfully generated album \"{}\".", username),
-            )
-            .await?;
-        let zip_path = zip_file.unwrap();
-        // Do not try to send an archive that exceed 20 MB
-        if fs::metadata(&zip_path).unwrap().len() > 20 * 1024 * 1024 {
-            warn!("An archive with all albums requested by user #{} exceeds 20 MB size limit and hasn't been sent", user_id);
-            bot.send_message(msg.chat.id, "❗ Archive size exceeds 20 MB and cannot be sent automatically. In order to get it, please contact bot owners.".to_string()).reply_to_message_id(success_msg.id).await?;
-        } else {
-            let waiting_msg = bot.send_message(msg.chat.id, "⌛️").await?;
-            let input_file = InputFile::file(&zip_path);
-            bot.send_document(msg.chat.id, input_file)
-                .reply_to_message_id(success_msg.id)
+    bot.delete_message(msg.chat.id, waiting_msg.id).await?;
+
+    if let Some(groups) = groups {
+        if groups.is_empty() {
+            bot.send_message(msg.chat.id, "✅ No near-identical photos found.")
                 .await?;
-            bot.delete_message(msg.chat.id, waiting_msg.id).await?;
-            info!(
-                "Sent an archive with album \"{}\" to user #{}",
-                username, user_id
-            );
-            delete_contents_of_folder(&config.result_folder).await?;
+            return Ok(());
+        }
+
+        // Telegram limits message length, so split long lists into several messages
+        const MAX_MESSAGE_LENGTH: usize = 4000;
+        let mut text = format!(
+            "🔁 Found {} groups of near-identical photos:\n",
+            groups.len()
+        );
+        for (index, group) in groups.iter().enumerate() {
+            let lines: Vec<String> = group
+                .iter()
+                .map(|photo| {
+                    if photo.album_username == "(default)" {
+                        format!(
+                            "• {} post #{} ({})\n",
+                            photo.album_username, photo.post_id, photo.file_name
+                        )
+                    } else {
+                        format!(
+                            "• https://t.me/{}/{}\n",
+                            photo.album_username, photo.post_id
+                        )
+                    }
+                })
+                .collect();
+            let header = format!("\nGroup {}:\n", index + 1);
+
+            // Start a group in a new message if it doesn't fit into the current one
+            let group_length = header.len() + lines.iter().map(String::len).sum::<usize>();
+            if text.len() + group_length > MAX_MESSAGE_LENGTH {
+                bot.send_message(msg.chat.id, &text)
+                    .disable_web_page_preview(true)
+                    .await?;
+                text.clear();
+            }
+            text.push_str(&header);
+
+            // A group too large for a single message is continued in the next ones
+            for line in lines {
+                if text.len() + line.len() > MAX_MESSAGE_LENGTH {
+                    bot.send_message(msg.chat.id, &text)
+                        .disable_web_page_preview(true)
+                        .await?;
+                    text = format!("Group {} (continued):\n", index + 1);
+                }
+                text.push_str(&line);
+            }
         }
-    } else if error_string == "Album not found!" {
-        bot.send_message(msg.chat.id, format!("❌ {}", error_string))
+
+        bot.send_message(msg.chat.id, text)
+            .disable_web_page_preview(true)
+            .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
             .await?;
     } else {
         bot.send_message(
             msg.chat.id,
-            format!("❌ Error generating album \"{}\"!", username),
+            "❌ Error searching for duplicates. Please contact bot owners!",
         )
         .await?;
     }
@@ -250,26 +809,32 @@ pub async fn generate(bot: Bot, msg: Message, config: &Config, username: String)
     Ok(())
 }
 
-pub async fn deleteall(bot: Bot, msg: Message, config: &Config) -> HandlerResult {
+pub async fn deleteall(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    usage_store: &UsageStore,
+) -> HandlerResult {
     let user_id = msg.chat.id.0 as u64;
     let mut ok_string: Option<String> = None;
-    let mut error_string = String::new();
+    let mut error: Option<ArchiveError> = None;
 
-    match delete_user_folders(user_id, &config.data_folder).await {
+    match delete_user_folders(user_id, &config.data_folder, &config.result_folder).await {
         Ok(res) => {
             ok_string = Some(res);
         }
         Err(err) => {
             error!("deleteall(): user #{}: {}", user_id, err);
-            error_string = err.to_string();
+            error = Some(err);
         }
     }
+    refresh_usage(usage_store, user_id).await;
 
     if let Some(message) = ok_string {
         bot.send_message(msg.chat.id, format!("✅ {}", message))
             .await?;
-    } else if error_string == "No data found!" {
-        bot.send_message(msg.chat.id, format!("❗ {}", error_string))
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
             .await?;
     } else {
         bot.send_message(
@@ -282,7 +847,13 @@ pub async fn deleteall(bot: Bot, msg: Message, config: &Config) -> HandlerResult
     Ok(())
 }
 
-pub async fn delete(bot: Bot, msg: Message, config: &Config, username: String) -> HandlerResult {
+pub async fn delete(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    usage_store: &UsageStore,
+    username: String,
+) -> HandlerResult {
     // Check if the username is not empty
     if username.is_empty() {
         bot.send_message(
@@ -295,6 +866,7 @@ pub async fn delete(bot: Bot, msg: Message, config: &Config, username: String) -
 
     let user_id = msg.chat.id.0 as u64;
     let mut ok_string: Option<String> = None;
+    let mut error: Option<ArchiveError> = None;
 
     match delete_user_album(username, user_id, &config.data_folder).await {
         Ok(res) => {
@@ -302,12 +874,17 @@ pub async fn delete(bot: Bot, msg: Message, config: &Config, username: String) -
         }
         Err(err) => {
             error!("delete(): user #{}: {}", user_id, err);
+            error = Some(err);
         }
     }
+    refresh_usage(usage_store, user_id).await;
 
     if let Some(message) = ok_string {
         bot.send_message(msg.chat.id, format!("✅ {}", message))
             .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
+            .await?;
     } else {
         bot.send_message(
             msg.chat.id,
@@ -320,85 +897,1013 @@ pub async fn delete(bot: Bot, msg: Message, config: &Config, username: String) -
     Ok(())
 }
 
-pub async fn reply_not_authorized(bot: Bot, msg: Message) -> HandlerResult {
-    bot.send_message(msg.chat.id, "❗ You are not authorized to use this bot.")
-        .await?;
-    Ok(())
-}
+pub async fn theme(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    usage_store: &UsageStore,
+    template_store: &TemplateStore,
+    args: String,
+) -> HandlerResult {
+    let user_id = msg.chat.id.0 as u64;
+    let templates = template_store.get();
+    let theme_names = templates
+        .themes
+        .keys()
+        .map(|name| format!("\"{}\"", name))
+        .collect::<Vec<_>>()
+        .join(", ");
 
-pub async fn reply(bot: Bot, msg: Message, config: &Config) -> HandlerResult {
-    if let Some(text) = msg.text() {
-        if text == "/start" {
+    let mut args = args.split_whitespace();
+    let (theme, username) = match (args.next(), args.next(), args.next()) {
+        (None, _, _) => {
+            // Without arguments, list the themes together with the current choice
+            let mut text = format!("🎨 Available themes: {}\n", theme_names);
+            match get_themes(user_id, &config.data_folder).await {
+                Ok((user_theme, album_themes)) => {
+                    text.push_str(&format!(
+                        "Your theme: \"{}\"",
+                        user_theme.as_deref().unwrap_or(DEFAULT_THEME)
+                    ));
+                    for (username, theme) in album_themes {
+                        text.push_str(&format!("\n• {}: \"{}\"", username, theme));
+                    }
+                }
+                Err(ArchiveError::NoData) => {
+                    text.push_str(&format!("Your theme: \"{}\"", DEFAULT_THEME));
+                }
+                Err(err) => {
+                    error!("theme(): user #{}: {}", user_id, err);
+                }
+            }
+            bot.send_message(msg.chat.id, text).await?;
             return Ok(());
-        } else if text.starts_with('/') {
+        }
+        (Some(theme), username, None) => (theme, username),
+        _ => {
             bot.send_message(
                 msg.chat.id,
-                "❌ Invalid command! Please call /help to see the list of available commands."
-                    .to_string(),
+                "❌ Specify theme name and optionally album username!".to_string(),
             )
             .await?;
             return Ok(());
         }
-    }
+    };
 
-    let waiting_msg = bot.send_message(msg.chat.id, "⌛️").await?;
+    let theme = if theme == "default" {
+        None
+    } else if templates.themes.contains_key(theme) {
+        Some(theme.to_string())
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "❗ Theme \"{}\" not found! Available themes: {}",
+                theme, theme_names
+            ),
+        )
+        .await?;
+        return Ok(());
+    };
 
-    let chat_id = msg.chat.id;
-    let msg_id = msg.id;
-    let mut ok_string: Option<&str> = None;
-    let mut error_string = String::new();
+    let mut ok_string: Option<String> = None;
+    let mut error: Option<ArchiveError> = None;
 
-    match add_new_post(
-        bot.clone(),
-        msg,
+    match set_theme(
+        user_id,
         &config.data_folder,
-        config.max_user_folder_size,
+        username.map(str::to_string),
+        theme,
     )
     .await
     {
-        Ok(_) => {
-            ok_string = Some("Message added to archive.");
+        Ok(res) => {
+            ok_string = Some(res);
         }
         Err(err) => {
-            error!("reply(): user #{}: {}", chat_id, err);
-            error_string = err.to_string();
+            error!("theme(): user #{}: {}", user_id, err);
+            error = Some(err);
         }
     }
+    refresh_usage(usage_store, user_id).await;
 
-    bot.delete_message(chat_id, waiting_msg.id).await?;
     if let Some(message) = ok_string {
-        bot.send_message(chat_id, format!("✅ {}", message))
-            .reply_to_message_id(msg_id)
+        bot.send_message(msg.chat.id, format!("✅ {}", message))
+            .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
             .await?;
     } else {
-        let error_strings: Vec<String> = vec![
-            "Post already exists!".to_string(),
-            "User folder cannot exceed \\d+ MB size limit!".to_string(),
-            "Photo file size exceeds \\d+ MB size limit!".to_string(),
-            "Video file size exceeds \\d+ MB size limit!".to_string(),
-        ];
-
-        let mut found = false;
-        for pattern in &error_strings {
-            let re = Regex::new(&format!("^{}$", pattern)).unwrap();
-            if re.is_match(&error_string) {
-                found = true;
-                break;
-            }
-        }
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error setting theme. Please contact bot owners!".to_string(),
+        )
+        .await?;
+    }
 
-        if found {
-            // Known error message
-            bot.send_message(chat_id, format!("❗ {}", error_string))
-                .reply_to_message_id(msg_id)
-                .await?;
+    Ok(())
+}
+
+pub async fn invite(
+    bot: Bot,
+    msg: Message,
+    access_store: &AccessStore,
+    args: String,
+) -> HandlerResult {
+    let mut role = Role::User;
+    let mut valid_for_hours: Option<u32> = None;
+
+    for arg in args.split_whitespace() {
+        if arg.eq_ignore_ascii_case("readonly") {
+            role = Role::ReadOnly;
+        } else if let Ok(hours) = arg.parse::<u32>() {
+            valid_for_hours = Some(hours);
         } else {
-            // Unknown error message
             bot.send_message(
-                chat_id,
-                "❌ Error adding message! Please contact bot owners!".to_string(),
+                msg.chat.id,
+                "❌ Invalid arguments! Use `/invite [readonly] [hours]`.",
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+
+    let user_id = msg.chat.id.0 as u64;
+    let mut new_invite: Option<NewInvite> = None;
+
+    match access_store.create_invite(user_id, role, valid_for_hours) {
+        Ok(i) => {
+            info!("Invite code created by user #{}", user_id);
+            new_invite = Some(i);
+        }
+        Err(err) => {
+            error!("invite(): user #{}: {}", user_id, err);
+        }
+    }
+
+    if let Some(new_invite) = new_invite {
+        let me = bot.get_me().await?;
+        let validity = match new_invite
+            .expires_at
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+        {
+            Some(expires_at) => format!("until {}", expires_at.format("%Y-%m-%d %H:%M UTC")),
+            None => "once".to_string(),
+        };
+
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "✅ Invite code created: {}\nLink: https://t.me/{}?start={}\nAccess: {}\nCan be used {}.",
+                new_invite.code,
+                me.username(),
+                new_invite.code,
+                new_invite.role,
+                validity
+            ),
+        )
+        .disable_web_page_preview(true)
+        .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error creating invite code. Please check bot logs!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn revoke(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    access_store: &AccessStore,
+    user: String,
+) -> HandlerResult {
+    let Some(revoked_user_id) = parse_user_id(&user) else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Specify user ID to revoke access from!".to_string(),
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let user_id = msg.chat.id.0 as u64;
+    let mut error: Option<ArchiveError> = None;
+
+    match access_store.revoke(revoked_user_id) {
+        Ok(()) => {
+            info!(
+                "Access for user #{} revoked by user #{}",
+                revoked_user_id, user_id
+            );
+        }
+        Err(err) => {
+            error!("revoke(): user #{}: {}", user_id, err);
+            error = Some(err);
+        }
+    }
+
+    let listed_in_config = config.allowed_users.contains(&revoked_user_id)
+        || config.admins.contains(&revoked_user_id)
+        || config.read_only_users.contains(&revoked_user_id);
+
+    // A runtime grant may have been revoked, but the config still gives the user access
+    if listed_in_config {
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "❗ User #{} is listed in the config file and has to be removed there.",
+                revoked_user_id
+            ),
+        )
+        .await?;
+    } else if error.is_none() {
+        bot.send_message(
+            msg.chat.id,
+            format!("✅ Access revoked for user #{}.", revoked_user_id),
+        )
+        .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
+            .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error revoking access. Please check bot logs!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn start(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    access_store: &AccessStore,
+    code: String,
+) -> HandlerResult {
+    let user_id = msg.chat.id.0 as u64;
+    let mut error: Option<ArchiveError> = None;
+
+    if get_role(config, access_store, user_id).is_some() && !config.restrict_access {
+        // Everybody has access already, nothing to redeem
+        bot.send_message(
+            msg.chat.id,
+            "✅ You already have access! Please call /help to see the list of available commands.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    match access_store.redeem_invite(&code, user_id) {
+        Ok(role) => {
+            info!(
+                "User #{} redeemed an invite code with {:?} role",
+                user_id, role
+            );
+        }
+        Err(err) => {
+            error!("start(): user #{}: {}", user_id, err);
+            error = Some(err);
+        }
+    }
+
+    if error.is_none() {
+        bot.send_message(
+            msg.chat.id,
+            "✅ Access granted! Please call /help to see the list of available commands.",
+        )
+        .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
+            .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error redeeming invite code. Please contact bot owners!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn get_storage_limits(config: &Config, access_store: &AccessStore, user_id: u64) -> StorageLimits {
+    let bot_api_limits = get_bot_api_limits(config);
+    StorageLimits {
+        max_user_folder_size_in_mb: get_user_quota(config, access_store, user_id),
+        max_data_folder_size_in_mb: config.max_data_folder_size,
+        min_free_disk_space_in_mb: config.min_free_disk_space,
+        max_photo_size_in_mb: bot_api_limits.max_photo_size_in_mb,
+        max_video_size_in_mb: bot_api_limits.max_video_size_in_mb,
+    }
+}
+
+async fn get_storage_usage(usage_store: &UsageStore, user_id: u64) -> StorageUsage {
+    StorageUsage {
+        user_folder_size: usage_store.get_user_folder_size(user_id).await,
+        data_folder_size: usage_store.get_data_folder_size(),
+    }
+}
+
+async fn add_usage(usage_store: &UsageStore, user_id: u64, added_size: u64) {
+    if let Err(err) = usage_store.add(user_id, added_size).await {
+        error!(
+            "Unable to update usage index for user #{}: {}",
+            user_id, err
+        );
+    }
+}
+
+async fn refresh_usage(usage_store: &UsageStore, user_id: u64) {
+    if let Err(err) = usage_store.refresh(user_id).await {
+        error!(
+            "Unable to update usage index for user #{}: {}",
+            user_id, err
+        );
+    }
+}
+
+fn parse_user_id(arg: &str) -> Option<u64> {
+    arg.trim().parse::<u64>().ok()
+}
+
+pub async fn users(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    access_store: &AccessStore,
+    usage_store: &UsageStore,
+) -> HandlerResult {
+    let user_id = msg.chat.id.0 as u64;
+
+    // Users may be known from the config, the runtime access list or their data folders
+    let mut user_ids: Vec<u64> = config
+        .admins
+        .iter()
+        .chain(config.allowed_users.iter())
+        .chain(config.read_only_users.iter())
+        .copied()
+        .chain(access_store.get_user_ids())
+        .collect();
+    match get_user_folder_ids(&config.data_folder) {
+        Ok(ids) => user_ids.extend(ids),
+        Err(err) => {
+            error!("users(): user #{}: {}", user_id, err);
+        }
+    }
+    user_ids.sort_unstable();
+    user_ids.dedup();
+
+    if user_ids.is_empty() {
+        bot.send_message(msg.chat.id, "❗ No users found!").await?;
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row!["User", "Role", "Size", "Limit"]);
+
+    for id in &user_ids {
+        let id = *id;
+        let user_folder_size = usage_store.get_user_folder_size(id).await;
+
+        let role = if access_store.is_banned(id) {
+            "banned".to_string()
+        } else {
+            get_role(config, access_store, id).map_or("-".to_string(), |role| role.to_string())
+        };
+
+        table.add_row(row![
+            id,
+            role,
+            convert_to_mb(user_folder_size),
+            get_user_quota(config, access_store, id)
+        ]);
+    }
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "<pre>{}
+Total occupied space: {} MB</pre>",
+            table,
+            convert_to_mb(usage_store.get_data_folder_size())
+        ),
+    )
+    .parse_mode(ParseMode::Html)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn usage(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    access_store: &AccessStore,
+    usage_store: &UsageStore,
+    user: String,
+) -> HandlerResult {
+    let user_id = msg.chat.id.0 as u64;
+
+    // Without an argument the caller's own usage is shown
+    let target_user_id = if user.trim().is_empty() {
+        user_id
+    } else if let Some(target_user_id) = parse_user_id(&user) {
+        target_user_id
+    } else {
+        bot.send_message(msg.chat.id, "❌ Specify user ID to show usage for!")
+            .await?;
+        return Ok(());
+    };
+
+    let mut albums: Option<Vec<AlbumUsage>> = None;
+    let mut error: Option<ArchiveError> = None;
+
+    match get_album_usage(target_user_id, &config.data_folder).await {
+        Ok(a) => {
+            albums = Some(a);
+        }
+        Err(err) => {
+            error!("usage(): user #{}: {}", user_id, err);
+            error = Some(err);
+        }
+    }
+
+    let user_folder_size = usage_store.get_user_folder_size(target_user_id).await;
+    let max_user_folder_size_in_mb = get_user_quota(config, access_store, target_user_id);
+    let summary = format!(
+        "{}: {}/{} MB ({}%)",
+        if target_user_id == user_id {
+            "Occupied space".to_string()
+        } else {
+            format!("User #{}", target_user_id)
+        },
+        convert_to_mb(user_folder_size),
+        max_user_folder_size_in_mb,
+        get_usage_percentage(user_folder_size, max_user_folder_size_in_mb)
+    );
+
+    if let Some(albums) = albums {
+        let mut table = Table::new();
+        table.add_row(row!["Username", "Photos", "Videos", "Total"]);
+        albums.iter().for_each(|album| {
+            table.add_row(row![
+                truncate_string(&album.username, 16),
+                convert_to_mb(album.photos_size),
+                convert_to_mb(album.videos_size),
+                convert_to_mb(album.total_size)
+            ]);
+        });
+        table.add_row(row![
+            "All albums",
+            convert_to_mb(albums.iter().map(|album| album.photos_size).sum()),
+            convert_to_mb(albums.iter().map(|album| album.videos_size).sum()),
+            convert_to_mb(user_folder_size)
+        ]);
+
+        bot.send_message(
+            msg.chat.id,
+            format!("<pre>{}\nSizes in MB:\n{}</pre>", summary, table),
+        )
+        .parse_mode(ParseMode::Html)
+        .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("{}\n❗ {}", summary, message))
+            .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error reading albums. Please contact bot owners!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn get_usage_percentage(user_folder_size: u64, max_user_folder_size_in_mb: u32) -> u64 {
+    // A limit of 0 MB leaves no space at all
+    (user_folder_size * 100)
+        .checked_div(max_user_folder_size_in_mb as u64 * 1024 * 1024)
+        .unwrap_or(100)
+}
+
+/// Warns the user once the folder size crosses one of the configured thresholds
+async fn warn_about_quota(
+    bot: &Bot,
+    chat_id: ChatId,
+    config: &Config,
+    access_store: &AccessStore,
+    previous_size: u64,
+    new_size: u64,
+) -> HandlerResult {
+    let user_id = chat_id.0 as u64;
+    let max_user_folder_size_in_mb = get_user_quota(config, access_store, user_id);
+    let previous_percentage = get_usage_percentage(previous_size, max_user_folder_size_in_mb);
+    let new_percentage = get_usage_percentage(new_size, max_user_folder_size_in_mb);
+
+    // Only warn when a threshold has just been crossed, so the same warning isn't repeated for every post
+    let Some(threshold) = config
+        .quota_warning_thresholds
+        .iter()
+        .map(|threshold| *threshold as u64)
+        .filter(|threshold| previous_percentage < *threshold && new_percentage >= *threshold)
+        .max()
+    else {
+        return Ok(());
+    };
+
+    let mut text = format!(
+        "⚠️ Your archive has reached {}% of the {} MB size limit ({}/{} MB).",
+        threshold,
+        max_user_folder_size_in_mb,
+        convert_to_mb(new_size),
+        max_user_folder_size_in_mb
+    );
+
+    match get_album_usage(user_id, &config.data_folder).await {
+        Ok(albums) => {
+            let largest_albums: Vec<String> = albums
+                .iter()
+                .take(3)
+                .map(|album| {
+                    format!(
+                        "{} ({} MB)",
+                        album.username,
+                        convert_to_mb(album.total_size)
+                    )
+                })
+                .collect();
+            text.push_str(&format!(
+                "\nConsider deleting some of the largest albums with /delete: {}",
+                largest_albums.join(", ")
+            ));
+        }
+        Err(err) => {
+            error!("warn_about_quota(): user #{}: {}", user_id, err);
+        }
+    }
+
+    bot.send_message(chat_id, text).await?;
+
+    Ok(())
+}
+
+pub async fn setquota(
+    bot: Bot,
+    msg: Message,
+    access_store: &AccessStore,
+    args: String,
+) -> HandlerResult {
+    let mut words = args.split_whitespace();
+    let (Some(target_user_id), Some(max_user_folder_size)) = (
+        words.next().and_then(parse_user_id),
+        words.next().and_then(|size| size.parse::<u32>().ok()),
+    ) else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Specify user ID and size limit in MB, e.g. `/setquota 123456 1000`!",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let user_id = msg.chat.id.0 as u64;
+
+    match access_store.set_quota(target_user_id, max_user_folder_size) {
+        Ok(()) => {
+            info!(
+                "Folder size limit for user #{} set to {} MB by user #{}",
+                target_user_id, max_user_folder_size, user_id
+            );
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "✅ Folder size limit for user #{} set to {} MB.",
+                    target_user_id, max_user_folder_size
+                ),
+            )
+            .await?;
+        }
+        Err(err) => {
+            error!("setquota(): user #{}: {}", user_id, err);
+            if let Some(message) = user_message(&err) {
+                bot.send_message(msg.chat.id, format!("❗ {}", message))
+                    .await?;
+            } else {
+                bot.send_message(
+                    msg.chat.id,
+                    "❌ Error setting folder size limit. Please check bot logs!",
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn purge(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    usage_store: &UsageStore,
+    user: String,
+) -> HandlerResult {
+    let Some(target_user_id) = parse_user_id(&user) else {
+        bot.send_message(msg.chat.id, "❌ Specify user ID to delete data for!")
+            .await?;
+        return Ok(());
+    };
+
+    let user_id = msg.chat.id.0 as u64;
+    let mut error: Option<ArchiveError> = None;
+
+    match delete_user_folders(target_user_id, &config.data_folder, &config.result_folder).await {
+        Ok(_) => {
+            info!(
+                "All data of user #{} deleted by user #{}",
+                target_user_id, user_id
+            );
+        }
+        Err(err) => {
+            error!("purge(): user #{}: {}", user_id, err);
+            error = Some(err);
+        }
+    }
+    refresh_usage(usage_store, target_user_id).await;
+
+    if error.is_none() {
+        bot.send_message(
+            msg.chat.id,
+            format!("✅ All data of user #{} deleted.", target_user_id),
+        )
+        .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
+            .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error deleting user data. Please check bot logs!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn ban(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    access_store: &AccessStore,
+    user: String,
+) -> HandlerResult {
+    let Some(target_user_id) = parse_user_id(&user) else {
+        bot.send_message(msg.chat.id, "❌ Specify user ID to ban!")
+            .await?;
+        return Ok(());
+    };
+
+    if get_role(config, access_store, target_user_id) == Some(Role::Admin) {
+        bot.send_message(msg.chat.id, "❗ Admins cannot be banned!")
+            .await?;
+        return Ok(());
+    }
+
+    let user_id = msg.chat.id.0 as u64;
+
+    match access_store.ban(target_user_id) {
+        Ok(()) => {
+            info!("User #{} banned by user #{}", target_user_id, user_id);
+            bot.send_message(msg.chat.id, format!("✅ User #{} banned.", target_user_id))
+                .await?;
+        }
+        Err(err) => {
+            error!("ban(): user #{}: {}", user_id, err);
+            bot.send_message(msg.chat.id, "❌ Error banning user. Please check bot logs!")
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn unban(
+    bot: Bot,
+    msg: Message,
+    access_store: &AccessStore,
+    user: String,
+) -> HandlerResult {
+    let Some(target_user_id) = parse_user_id(&user) else {
+        bot.send_message(msg.chat.id, "❌ Specify user ID to unban!")
+            .await?;
+        return Ok(());
+    };
+
+    let user_id = msg.chat.id.0 as u64;
+    let mut error: Option<ArchiveError> = None;
+
+    match access_store.unban(target_user_id) {
+        Ok(()) => {
+            info!("User #{} unbanned by user #{}", target_user_id, user_id);
+        }
+        Err(err) => {
+            error!("unban(): user #{}: {}", user_id, err);
+            error = Some(err);
+        }
+    }
+
+    if error.is_none() {
+        bot.send_message(
+            msg.chat.id,
+            format!("✅ User #{} unbanned.", target_user_id),
+        )
+        .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.send_message(msg.chat.id, format!("❗ {}", message))
+            .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            "❌ Error unbanning user. Please check bot logs!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn reply_not_authorized(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(msg.chat.id, "❗ You are not authorized to use this bot.")
+        .await?;
+    Ok(())
+}
+
+pub async fn reply_not_permitted(bot: Bot, msg: Message) -> HandlerResult {
+    bot.send_message(
+        msg.chat.id,
+        "❗ You are not allowed to use this command. Please call /help to see the list of available commands.",
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn callback_not_authorized(bot: Bot, q: CallbackQuery) -> HandlerResult {
+    bot.answer_callback_query(q.id)
+        .text("❗ You are not authorized to use this bot.")
+        .await?;
+    Ok(())
+}
+
+fn describe_duplicate(original: &MediaLocation) -> String {
+    let mut text = format!(
+        "❗ This media is already archived in album \"{}\" (post #{}).",
+        original.album_title, original.post_id
+    );
+    if original.album_username != "(default)" {
+        text.push_str(&format!(
+            "\nOriginal post: https://t.me/{}/{}",
+            original.album_username, original.post_id
+        ));
+    }
+    text.push_str("\n\nLink the existing file or store a separate copy?");
+    text
+}
+
+pub async fn duplicate_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    config: &Config,
+    access_store: &AccessStore,
+    usage_store: &UsageStore,
+    role: Role,
+) -> HandlerResult {
+    if role < Role::User {
+        bot.answer_callback_query(q.id)
+            .text("❗ Read-only users cannot add posts to the archive.")
+            .await?;
+        return Ok(());
+    }
+
+    let duplicate_policy = match q.data.as_deref() {
+        Some("duplicate:link") => DuplicatePolicy::Link,
+        Some("duplicate:copy") => DuplicatePolicy::Copy,
+        _ => {
+            bot.answer_callback_query(q.id).await?;
+            return Ok(());
+        }
+    };
+    bot.answer_callback_query(q.id).await?;
+
+    // The prompt is sent as a reply to the forwarded message, so the original media is still available
+    let Some(prompt) = q.message else {
+        return Ok(());
+    };
+    let Some(original_msg) = prompt.reply_to_message().cloned() else {
+        bot.edit_message_text(
+            prompt.chat.id,
+            prompt.id,
+            "❌ Original message is no longer available!",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let user_id = prompt.chat.id.0 as u64;
+    let mut ok_string: Option<&str> = None;
+    let mut error: Option<ArchiveError> = None;
+    let mut folder_growth: Option<(u64, u64)> = None;
+
+    match add_new_post(
+        bot.clone(),
+        original_msg,
+        &config.data_folder,
+        &get_storage_limits(config, access_store, user_id),
+        &get_storage_usage(usage_store, user_id).await,
+        duplicate_policy,
+        config.perceptual_hashing,
+    )
+    .await
+    {
+        Ok(added_size) => {
+            let previous_size = usage_store.get_user_folder_size(user_id).await;
+            add_usage(usage_store, user_id, added_size).await;
+            folder_growth = Some((previous_size, previous_size + added_size));
+            ok_string = Some(match duplicate_policy {
+                DuplicatePolicy::Link => "Message added to archive, media linked to the original.",
+                _ => "Message added to archive.",
+            });
+        }
+        Err(err) => {
+            error!("duplicate_callback(): user #{}: {}", user_id, err);
+            error = Some(err);
+        }
+    }
+
+    if let Some(message) = ok_string {
+        bot.edit_message_text(prompt.chat.id, prompt.id, format!("✅ {}", message))
+            .await?;
+        if let Some((previous_size, new_size)) = folder_growth {
+            warn_about_quota(
+                &bot,
+                prompt.chat.id,
+                config,
+                access_store,
+                previous_size,
+                new_size,
+            )
+            .await?;
+        }
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        bot.edit_message_text(prompt.chat.id, prompt.id, format!("❗ {}", message))
+            .await?;
+    } else {
+        bot.edit_message_text(
+            prompt.chat.id,
+            prompt.id,
+            "❌ Error adding message! Please contact bot owners!",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn reply(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    access_store: &AccessStore,
+    usage_store: &UsageStore,
+    role: Role,
+) -> HandlerResult {
+    if let Some(text) = msg.text() {
+        if text == "/start" {
+            return Ok(());
+        } else if text.starts_with('/') {
+            bot.send_message(
+                msg.chat.id,
+                "❌ Invalid command! Please call /help to see the list of available commands."
+                    .to_string(),
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+
+    if role < Role::User {
+        bot.send_message(
+            msg.chat.id,
+            "❗ Read-only users cannot add posts to the archive.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let waiting_msg = bot.send_message(msg.chat.id, "⌛️").await?;
+
+    let chat_id = msg.chat.id;
+    let msg_id = msg.id;
+    let mut ok_string: Option<&str> = None;
+    let mut error: Option<ArchiveError> = None;
+    let mut folder_growth: Option<(u64, u64)> = None;
+
+    match add_new_post(
+        bot.clone(),
+        msg,
+        &config.data_folder,
+        &get_storage_limits(config, access_store, chat_id.0 as u64),
+        &get_storage_usage(usage_store, chat_id.0 as u64).await,
+        DuplicatePolicy::Reject,
+        config.perceptual_hashing,
+    )
+    .await
+    {
+        Ok(added_size) => {
+            let previous_size = usage_store.get_user_folder_size(chat_id.0 as u64).await;
+            add_usage(usage_store, chat_id.0 as u64, added_size).await;
+            folder_growth = Some((previous_size, previous_size + added_size));
+            ok_string = Some("Message added to archive.");
+        }
+        Err(err) => {
+            error!("reply(): user #{}: {}", chat_id, err);
+            error = Some(err);
+        }
+    }
+
+    bot.delete_message(chat_id, waiting_msg.id).await?;
+    if let Some(message) = ok_string {
+        bot.send_message(chat_id, format!("✅ {}", message))
+            .reply_to_message_id(msg_id)
+            .await?;
+        if let Some((previous_size, new_size)) = folder_growth {
+            warn_about_quota(&bot, chat_id, config, access_store, previous_size, new_size).await?;
+        }
+    } else if let Some(ArchiveError::Duplicate(original)) = &error {
+        let keyboard = InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback("🔗 Link", "duplicate:link"),
+            InlineKeyboardButton::callback("📄 Copy", "duplicate:copy"),
+        ]]);
+        bot.send_message(chat_id, describe_duplicate(original))
+            .reply_to_message_id(msg_id)
+            .reply_markup(keyboard)
+            .await?;
+    } else if let Some(message) = error.as_ref().and_then(user_message) {
+        // Known error message
+        bot.send_message(chat_id, format!("❗ {}", message))
+            .reply_to_message_id(msg_id)
+            .await?;
+    } else {
+        // Unknown error message
+        bot.send_message(
+            chat_id,
+            "❌ Error adding message! Please contact bot owners!".to_string(),
+        )
+        .reply_to_message_id(msg_id)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn reloadtemplates(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    template_store: &TemplateStore,
+) -> HandlerResult {
+    let user_id = msg.chat.id.0 as u64;
+
+    // Invalid templates are rejected and the current ones keep being used
+    match load_templates(config.template_folder.as_deref()) {
+        Ok(templates) => {
+            let theme_count = templates.themes.len();
+            template_store.set(templates);
+            info!("Templates reloaded by user #{}", user_id);
+            bot.send_message(
+                msg.chat.id,
+                format!("✅ Templates of {} themes reloaded.", theme_count),
+            )
+            .await?;
+        }
+        Err(err) => {
+            error!("reloadtemplates(): user #{}: {}", user_id, err);
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "❌ Templates are invalid, the previous ones are kept: {}",
+                    err
+                ),
             )
-            .reply_to_message_id(msg_id)
             .await?;
         }
     }
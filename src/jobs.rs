@@ -0,0 +1,241 @@
+use log2::*;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use teloxide::prelude::*;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::AbortHandle;
+
+/// Minimum delay between two edits of a status message, to stay within Telegram rate limits
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Stage of a background job, shown in its status message and in `/jobs`
+#[derive(Debug, Clone)]
+pub enum JobProgress {
+    Queued,
+    Started,
+    Rendering { album: usize, album_count: usize },
+    Zipping { album_count: usize },
+    Sending,
+}
+
+impl fmt::Display for JobProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobProgress::Queued => write!(f, "waiting in queue..."),
+            JobProgress::Started => write!(f, "starting..."),
+            JobProgress::Rendering { album, album_count } => {
+                write!(f, "album {}/{}, rendering...", album, album_count)
+            }
+            JobProgress::Zipping { album_count } => {
+                write!(f, "album {}/{}, zipping...", album_count, album_count)
+            }
+            JobProgress::Sending => write!(f, "sending archive..."),
+        }
+    }
+}
+
+/// Handle through which a job reports its progress and finds out whether it has been cancelled
+#[derive(Clone)]
+pub struct ProgressSender {
+    sender: watch::Sender<JobProgress>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ProgressSender {
+    pub fn send_replace(&self, progress: JobProgress) {
+        self.sender.send_replace(progress);
+    }
+
+    /// Flag set once the job is cancelled. Blocking work keeps running when the job is aborted,
+    /// so it has to check the flag itself
+    pub fn cancelled(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+}
+
+/// A handle nobody listens to, for running a job's work outside of the queue, e.g. in benchmarks
+impl Default for ProgressSender {
+    fn default() -> Self {
+        ProgressSender {
+            sender: watch::channel(JobProgress::Queued).0,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+struct Job {
+    id: u64,
+    user_id: u64,
+    description: String,
+    status_message: Message,
+    progress: watch::Receiver<JobProgress>,
+    cancelled: Arc<AtomicBool>,
+    abort_handle: AbortHandle,
+}
+
+pub struct JobInfo {
+    pub id: u64,
+    pub user_id: u64,
+    pub description: String,
+    pub progress: JobProgress,
+}
+
+/// Runs long jobs such as album generation in the background, at most `max_concurrent_jobs` at a time
+#[derive(Clone)]
+pub struct JobQueue {
+    semaphore: Arc<Semaphore>,
+    jobs: Arc<Mutex<Vec<Job>>>,
+    next_id: Arc<AtomicU64>,
+    /// Address of the download server jobs hand out links to. Like `max_concurrent_jobs`,
+    /// it is taken from the config on startup and isn't changed by reloads
+    download_server_address: Option<SocketAddr>,
+}
+
+impl JobQueue {
+    pub fn new(max_concurrent_jobs: usize, download_server_address: Option<SocketAddr>) -> Self {
+        JobQueue {
+            semaphore: Arc::new(Semaphore::new(max_concurrent_jobs)),
+            jobs: Arc::new(Mutex::new(Vec::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            download_server_address,
+        }
+    }
+
+    /// Returns the address of the download server, `None` if it is disabled
+    pub fn download_server_address(&self) -> Option<SocketAddr> {
+        self.download_server_address
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Job>> {
+        // Jobs are only added and removed as a whole, so a poisoned lock still holds valid data
+        match self.jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Queues a job, whose progress is shown by editing `status_message` until the job finishes
+    pub fn spawn<F, Fut>(
+        &self,
+        bot: Bot,
+        status_message: Message,
+        user_id: u64,
+        description: String,
+        job: F,
+    ) -> u64
+    where
+        F: FnOnce(ProgressSender) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, progress) = watch::channel(JobProgress::Queued);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let progress_sender = ProgressSender {
+            sender,
+            cancelled: cancelled.clone(),
+        };
+        let job = job(progress_sender.clone());
+
+        let semaphore = self.semaphore.clone();
+        let job_queue = self.clone();
+        let report = report_progress(bot.clone(), status_message.clone(), id, progress.clone());
+
+        // Keep the list locked until the job is added, so that a job finishing right away can't miss its entry
+        let mut jobs = self.lock();
+        let handle = tokio::spawn(async move {
+            let run = async move {
+                // The semaphore is never closed, so acquiring can't fail
+                let _permit = semaphore.acquire_owned().await;
+                progress_sender.send_replace(JobProgress::Started);
+                job.await;
+            };
+            // Reporting ends once the job and its progress sender are dropped
+            tokio::join!(run, report);
+
+            if let Some(job) = job_queue.remove(id) {
+                if let Err(e) = bot
+                    .delete_message(job.status_message.chat.id, job.status_message.id)
+                    .await
+                {
+                    warn!("Unable to delete status message of job #{}: {}", id, e);
+                }
+            }
+        });
+
+        jobs.push(Job {
+            id,
+            user_id,
+            description,
+            status_message,
+            progress,
+            cancelled,
+            abort_handle: handle.abort_handle(),
+        });
+
+        id
+    }
+
+    fn remove(&self, id: u64) -> Option<Job> {
+        let mut jobs = self.lock();
+        let index = jobs.iter().position(|job| job.id == id)?;
+        Some(jobs.remove(index))
+    }
+
+    /// Lists jobs of the given user, or all jobs if no user is specified
+    pub fn list(&self, user_id: Option<u64>) -> Vec<JobInfo> {
+        self.lock()
+            .iter()
+            .filter(|job| user_id.is_none_or(|user_id| job.user_id == user_id))
+            .map(|job| JobInfo {
+                id: job.id,
+                user_id: job.user_id,
+                description: job.description.clone(),
+                progress: job.progress.borrow().clone(),
+            })
+            .collect()
+    }
+
+    /// Stops a job of the given user, or of any user if no user is specified.
+    /// Returns the status message of the cancelled job
+    pub fn cancel(&self, id: u64, user_id: Option<u64>) -> Option<Message> {
+        let mut jobs = self.lock();
+        let index = jobs
+            .iter()
+            .position(|job| job.id == id && user_id.is_none_or(|user_id| job.user_id == user_id))?;
+        let job = jobs.remove(index);
+
+        // Aborting drops the job future, but not blocking work it has started, such as zipping.
+        // That work stops at its next read once it sees the flag, and deletes the workspace when it returns
+        job.cancelled.store(true, Ordering::Relaxed);
+        job.abort_handle.abort();
+        info!("Job #{} of user #{} cancelled", job.id, job.user_id);
+
+        Some(job.status_message)
+    }
+}
+
+async fn report_progress(
+    bot: Bot,
+    status_message: Message,
+    id: u64,
+    mut progress: watch::Receiver<JobProgress>,
+) {
+    loop {
+        let text = format!("⏳ Job #{}: {}", id, *progress.borrow_and_update());
+        if let Err(e) = bot
+            .edit_message_text(status_message.chat.id, status_message.id, text)
+            .await
+        {
+            warn!("Unable to update status message of job #{}: {}", id, e);
+        }
+
+        tokio::time::sleep(PROGRESS_UPDATE_INTERVAL).await;
+        if progress.changed().await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,8 @@
+pub mod access;
+pub mod download;
+pub mod handlers;
+pub mod jobs;
+pub mod operations;
+pub mod templates;
+pub mod usage;
+pub mod utils;
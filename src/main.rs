@@ -1,74 +1,320 @@
+use clap::Parser;
 use log2::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use teloxide::prelude::*;
 
-mod handlers;
-mod operations;
-mod utils;
+use archivegrambot::access::{self, AccessStore, Role};
+use archivegrambot::download;
+use archivegrambot::handlers::{self, Command};
+use archivegrambot::jobs::JobQueue;
+use archivegrambot::templates::{self, TemplateStore};
+use archivegrambot::usage::UsageStore;
+use archivegrambot::utils::{self, Config, SharedConfig};
 
-use handlers::Command;
-use utils::Config;
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the config file
+    #[arg(
+        short,
+        long,
+        env = "ARCHIVEGRAMBOT_CONFIG",
+        default_value = "config.toml"
+    )]
+    config: String,
+
+    /// Folder with album templates, overrides `template_folder` from the config file
+    #[arg(short, long)]
+    templates: Option<String>,
+
+    /// Log level
+    #[arg(
+        short,
+        long,
+        env = "ARCHIVEGRAMBOT_LOG_LEVEL",
+        default_value = "info",
+        value_parser = ["off", "error", "warn", "info", "debug", "trace"]
+    )]
+    log_level: String,
+}
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+
     // Read the config file
-    let config = utils::load_config("config.toml");
+    let config = match utils::load_config(&args.config, args.templates.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Unable to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let _log2 = log2::open(&config.log_path)
         .module(false)
-        .level("info")
+        .level(&args.log_level)
         .start();
 
     info!("Starting bot...");
 
-    let bot = Bot::new(&config.teloxide_token);
+    let access_store = match AccessStore::load(&config.data_folder) {
+        Ok(access_store) => access_store,
+        Err(e) => {
+            error!("Unable to load runtime access list: {}", e);
+            eprintln!("Unable to load runtime access list: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Broken templates would only show up when an album is generated, so refuse to start instead
+    let template_store = match templates::load_templates(config.template_folder.as_deref()) {
+        Ok(templates) => TemplateStore::new(templates),
+        Err(e) => {
+            error!("Unable to load templates: {}", e);
+            eprintln!("Unable to load templates: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let usage_store = match UsageStore::load(&config.data_folder) {
+        Ok(usage_store) => usage_store,
+        Err(e) => {
+            error!("Unable to load usage index: {}", e);
+            eprintln!("Unable to load usage index: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = utils::remove_stale_workspaces(&config.result_folder) {
+        warn!("Unable to remove workspaces of interrupted jobs: {}", e);
+    }
+
+    let job_queue = JobQueue::new(config.max_concurrent_jobs, config.download_server_address);
+
+    let mut bot = Bot::new(&config.teloxide_token);
+    if let Some(bot_api_url) = &config.bot_api_url {
+        info!("Using Bot API server at {}", bot_api_url);
+        bot = bot.set_api_url(bot_api_url.clone());
+    }
+
+    let command_handler =
+        teloxide::filter_command::<Command, _>()
+            .branch(
+                dptree::filter(|cmd: Command, role: Role| role < cmd.required_role())
+                    .endpoint(handlers::reply_not_permitted),
+            )
+            .branch(dptree::case![Command::Help].endpoint(handlers::help))
+            .branch(dptree::case![Command::ShowAlbums].endpoint(
+                |bot,
+                 msg,
+                 config: Arc<Config>,
+                 access_store: AccessStore,
+                 usage_store: UsageStore| async move {
+                    handlers::showalbums(bot, msg, &config, &access_store, &usage_store).await
+                },
+            ))
+            .branch(dptree::case![Command::ConsolidateAll].endpoint(
+                |bot, msg, config: Arc<Config>, usage_store: UsageStore| async move {
+                    handlers::consolidateall(bot, msg, &config, &usage_store).await
+                },
+            ))
+            .branch(dptree::case![Command::GenerateAll(args)].endpoint(
+                |bot,
+                 msg,
+                 args,
+                 config: Arc<Config>,
+                 job_queue: JobQueue,
+                 template_store: TemplateStore| async move {
+                    handlers::generateall(bot, msg, &config, &job_queue, &template_store, args)
+                        .await
+                },
+            ))
+            .branch(dptree::case![Command::Generate(args)].endpoint(
+                |bot,
+                 msg,
+                 args,
+                 config: Arc<Config>,
+                 job_queue: JobQueue,
+                 template_store: TemplateStore| async move {
+                    handlers::generate(bot, msg, &config, &job_queue, &template_store, args).await
+                },
+            ))
+            .branch(dptree::case![Command::Jobs].endpoint(
+                |bot, msg, job_queue: JobQueue, role: Role| async move {
+                    handlers::jobs(bot, msg, &job_queue, role).await
+                },
+            ))
+            .branch(dptree::case![Command::Cancel(job)].endpoint(
+                |bot, msg, job, job_queue: JobQueue, role: Role| async move {
+                    handlers::cancel(bot, msg, &job_queue, role, job).await
+                },
+            ))
+            .branch(dptree::case![Command::Duplicates(username)].endpoint(
+                |bot, msg, username, config: Arc<Config>| async move {
+                    handlers::duplicates(bot, msg, &config, username).await
+                },
+            ))
+            .branch(dptree::case![Command::DeleteAll].endpoint(
+                |bot, msg, config: Arc<Config>, usage_store: UsageStore| async move {
+                    handlers::deleteall(bot, msg, &config, &usage_store).await
+                },
+            ))
+            .branch(dptree::case![Command::Delete(username)].endpoint(
+                |bot, msg, username, config: Arc<Config>, usage_store: UsageStore| async move {
+                    handlers::delete(bot, msg, &config, &usage_store, username).await
+                },
+            ))
+            .branch(dptree::case![Command::Theme(args)].endpoint(
+                |bot,
+                 msg,
+                 args,
+                 config: Arc<Config>,
+                 usage_store: UsageStore,
+                 template_store: TemplateStore| async move {
+                    handlers::theme(bot, msg, &config, &usage_store, &template_store, args).await
+                },
+            ))
+            .branch(dptree::case![Command::Invite(args)].endpoint(
+                |bot, msg, args, access_store: AccessStore| async move {
+                    handlers::invite(bot, msg, &access_store, args).await
+                },
+            ))
+            .branch(dptree::case![Command::Revoke(user)].endpoint(
+                |bot, msg, user, config: Arc<Config>, access_store: AccessStore| async move {
+                    handlers::revoke(bot, msg, &config, &access_store, user).await
+                },
+            ))
+            .branch(dptree::case![Command::Users].endpoint(
+                |bot,
+                 msg,
+                 config: Arc<Config>,
+                 access_store: AccessStore,
+                 usage_store: UsageStore| async move {
+                    handlers::users(bot, msg, &config, &access_store, &usage_store).await
+                },
+            ))
+            .branch(dptree::case![Command::Usage(user)].endpoint(
+                |bot,
+                 msg,
+                 user,
+                 config: Arc<Config>,
+                 access_store: AccessStore,
+                 usage_store: UsageStore| async move {
+                    handlers::usage(bot, msg, &config, &access_store, &usage_store, user).await
+                },
+            ))
+            .branch(dptree::case![Command::SetQuota(args)].endpoint(
+                |bot, msg, args, access_store: AccessStore| async move {
+                    handlers::setquota(bot, msg, &access_store, args).await
+                },
+            ))
+            .branch(dptree::case![Command::Purge(user)].endpoint(
+                |bot, msg, user, config: Arc<Config>, usage_store: UsageStore| async move {
+                    handlers::purge(bot, msg, &config, &usage_store, user).await
+                },
+            ))
+            .branch(dptree::case![Command::Ban(user)].endpoint(
+                |bot, msg, user, config: Arc<Config>, access_store: AccessStore| async move {
+                    handlers::ban(bot, msg, &config, &access_store, user).await
+                },
+            ))
+            .branch(dptree::case![Command::Unban(user)].endpoint(
+                |bot, msg, user, access_store: AccessStore| async move {
+                    handlers::unban(bot, msg, &access_store, user).await
+                },
+            ))
+            .branch(dptree::case![Command::ReloadTemplates].endpoint(
+                |bot, msg, config: Arc<Config>, template_store: TemplateStore| async move {
+                    handlers::reloadtemplates(bot, msg, &config, &template_store).await
+                },
+            ));
 
-    let command_handler = teloxide::filter_command::<Command, _>()
-        .branch(dptree::case![Command::Help].endpoint(handlers::help))
-        .branch(dptree::case![Command::ShowAlbums].endpoint(
-            |bot, msg, config: Config| async move { handlers::showalbums(bot, msg, &config).await },
-        ))
+    let message_handler = Update::filter_message()
+        // Invite codes are passed via deep links as `/start <code>`
         .branch(
-            dptree::case![Command::ConsolidateAll].endpoint(
-                |bot, msg, config: Config| async move {
-                    handlers::consolidateall(bot, msg, &config).await
+            dptree::filter_map(|msg: Message| {
+                msg.text()
+                    .and_then(|text| text.strip_prefix("/start "))
+                    .map(|code| code.trim().to_string())
+                    .filter(|code| !code.is_empty())
+            })
+            .endpoint(
+                |bot, msg, code, config: Arc<Config>, access_store: AccessStore| async move {
+                    handlers::start(bot, msg, &config, &access_store, code).await
                 },
             ),
         )
         .branch(
-            dptree::case![Command::GenerateAll].endpoint(|bot, msg, config: Config| async move {
-                handlers::generateall(bot, msg, &config).await
-            }),
-        )
-        .branch(dptree::case![Command::Generate(username)].endpoint(
-            |bot, msg, username, config: Config| async move {
-                handlers::generate(bot, msg, &config, username).await
-            },
-        ))
-        .branch(
-            dptree::case![Command::DeleteAll].endpoint(|bot, msg, config: Config| async move {
-                handlers::deleteall(bot, msg, &config).await
-            }),
+            dptree::filter_map(
+                |msg: Message, config: Arc<Config>, access_store: AccessStore| {
+                    access::get_role(&config, &access_store, msg.chat.id.0 as u64)
+                },
+            )
+            .branch(command_handler)
+            .branch(dptree::endpoint(
+                |bot,
+                 msg,
+                 config: Arc<Config>,
+                 access_store: AccessStore,
+                 usage_store: UsageStore,
+                 role: Role| async move {
+                    handlers::reply(bot, msg, &config, &access_store, &usage_store, role).await
+                },
+            )),
         )
-        .branch(dptree::case![Command::Delete(username)].endpoint(
-            |bot, msg, username, config: Config| async move {
-                handlers::delete(bot, msg, &config, username).await
-            },
-        ));
+        .branch(dptree::endpoint(handlers::reply_not_authorized));
 
-    let handler = Update::filter_message()
+    let callback_handler = Update::filter_callback_query()
         .branch(
-            dptree::filter(|msg: Message, config: Config| {
-                config.restrict_access && !config.allowed_users.contains(&(msg.chat.id.0 as u64))
-            })
-            .endpoint(handlers::reply_not_authorized),
+            dptree::filter_map(
+                |q: CallbackQuery, config: Arc<Config>, access_store: AccessStore| {
+                    access::get_role(&config, &access_store, q.from.id.0)
+                },
+            )
+            .endpoint(
+                |bot,
+                 q,
+                 config: Arc<Config>,
+                 access_store: AccessStore,
+                 usage_store: UsageStore,
+                 role: Role| async move {
+                    handlers::duplicate_callback(bot, q, &config, &access_store, &usage_store, role)
+                        .await
+                },
+            ),
         )
-        .branch(command_handler)
-        .branch(dptree::endpoint(|bot, msg, config: Config| async move {
-            handlers::reply(bot, msg, &config).await
-        }));
+        .branch(dptree::endpoint(handlers::callback_not_authorized));
+
+    // Take a snapshot of the current config for each update, so that a reload
+    // never changes settings in the middle of processing a message
+    let handler = dptree::entry()
+        .map(|shared_config: SharedConfig| shared_config.get())
+        .branch(message_handler)
+        .branch(callback_handler);
+
+    let shared_config = SharedConfig::new(config);
+    tokio::spawn(watch_config(
+        shared_config.clone(),
+        template_store.clone(),
+        args.config.clone(),
+        args.templates.clone(),
+    ));
+    tokio::spawn(reconcile_usage(shared_config.clone(), usage_store.clone()));
+    if let Some(address) = job_queue.download_server_address() {
+        tokio::spawn(download::serve(shared_config.clone(), address));
+        tokio::spawn(remove_expired_downloads(shared_config.clone()));
+    }
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![config])
+        .dependencies(dptree::deps![
+            shared_config,
+            access_store,
+            usage_store,
+            job_queue,
+            template_store
+        ])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -76,3 +322,138 @@ async fn main() {
 
     info!("Stopping bot...");
 }
+
+/// Reloads the config on SIGHUP or when the config file is modified
+async fn watch_config(
+    shared_config: SharedConfig,
+    template_store: TemplateStore,
+    file: String,
+    templates: Option<String>,
+) {
+    let modified_time = |file: &str| {
+        std::fs::metadata(file)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+
+    let mut last_modified: Option<SystemTime> = modified_time(&file);
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            warn!(
+                "Unable to listen for SIGHUP, config is only reloaded on file change: {}",
+                e
+            );
+            None
+        }
+    };
+
+    loop {
+        #[cfg(unix)]
+        let hangup_received = async {
+            match hangup.as_mut() {
+                Some(signal) => signal.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = hangup_received => {
+                info!("Received SIGHUP, reloading config...");
+            }
+            _ = interval.tick() => {
+                let modified = modified_time(&file);
+                if modified == last_modified {
+                    continue;
+                }
+                info!("Config file \"{}\" has changed, reloading config...", file);
+            }
+        }
+
+        last_modified = modified_time(&file);
+
+        match utils::load_config(&file, templates.as_deref()) {
+            Ok(mut config) => {
+                let current_config = shared_config.get();
+                if config.teloxide_token != current_config.teloxide_token
+                    || config.log_path != current_config.log_path
+                    || config.max_concurrent_jobs != current_config.max_concurrent_jobs
+                    || config.download_server_address != current_config.download_server_address
+                    || config.bot_api_url != current_config.bot_api_url
+                    || config.data_folder != current_config.data_folder
+                    || config.result_folder != current_config.result_folder
+                {
+                    warn!(
+                        "Changes of \"teloxide_token\", \"log_path\", \"max_concurrent_jobs\", \
+                         \"download_server_address\", \"bot_api_url\", \"data_folder\" \
+                         and \"result_folder\" take effect only after restart."
+                    );
+                }
+                // The access list, usage index and download server keep using the folders they were started with
+                config.data_folder = current_config.data_folder.clone();
+                config.result_folder = current_config.result_folder.clone();
+                if config.template_folder != current_config.template_folder {
+                    // Keep the previous config as well, so that it still matches the templates in use
+                    match templates::load_templates(config.template_folder.as_deref()) {
+                        Ok(templates) => {
+                            template_store.set(templates);
+                            info!("Templates reloaded.");
+                        }
+                        Err(e) => {
+                            error!(
+                                "Unable to load templates, keeping the previous config: {}",
+                                e
+                            );
+                            continue;
+                        }
+                    }
+                }
+                shared_config.set(config);
+                info!("Config reloaded.");
+            }
+            Err(e) => {
+                error!("Unable to reload config, keeping the previous one: {}", e);
+            }
+        }
+    }
+}
+
+/// Periodically rebuilds the usage index from disk to correct any drift of incremental updates
+async fn reconcile_usage(shared_config: SharedConfig, usage_store: UsageStore) {
+    loop {
+        let interval = shared_config.get().usage_reconciliation_interval;
+        tokio::time::sleep(Duration::from_secs(interval * 60)).await;
+
+        match usage_store.reconcile().await {
+            Ok(()) => info!("Usage index reconciled with data folder."),
+            Err(e) => error!("Unable to reconcile usage index: {}", e),
+        }
+    }
+}
+
+/// Periodically deletes archives whose download links have expired
+async fn remove_expired_downloads(shared_config: SharedConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let config = shared_config.get();
+        let result = tokio::task::spawn_blocking(move || {
+            download::remove_expired_archives(&config.result_folder, config.download_link_lifetime)
+        })
+        .await;
+
+        match result {
+            Ok(Ok(0)) => {}
+            Ok(Ok(removed)) => info!("Removed {} archives with expired download links.", removed),
+            Ok(Err(e)) => error!("Unable to remove expired archives: {}", e),
+            Err(e) => error!("Unable to remove expired archives: {}", e),
+        }
+    }
+}
@@ -2,20 +2,35 @@ use chrono::prelude::*;
 use log2::*;
 use mime::Mime;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt;
-use std::io::prelude::*;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{fs, fs::File};
-use teloxide::{net::Download, requests::Requester, types::Message, Bot};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard};
+use teloxide::{
+    net::Download,
+    requests::Requester,
+    types::{FileMeta, Message},
+    Bot, DownloadError, RequestError,
+};
+use tempfile::TempDir;
 use tera::Context;
-use tera::Tera;
 use tokio::fs::File as FileAsync;
 
-use crate::utils::{convert_to_mb, copy_dir_all, get_folder_size, zip_folder};
+use crate::jobs::{JobProgress, ProgressSender};
+use crate::templates::{write_assets, Templates, Theme, ALBUM_TEMPLATE};
+use crate::utils::{
+    convert_to_mb, create_workspace, get_file_hash, get_folder_size, get_perceptual_hash,
+    link_or_copy_file, sync_dir, zip_files, Compression,
+};
 
+#[derive(Debug, Clone, Copy)]
 pub enum FileType {
     Photo,
     Video,
@@ -30,15 +45,323 @@ impl fmt::Display for FileType {
     }
 }
 
+/// What to do when an incoming media file is already present in one of the user's albums
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Reject,
+    Link,
+    Copy,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaLocation {
+    pub album_username: String,
+    pub album_title: String,
+    pub post_id: i32,
+    media: MediaRecord,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Duplicate(Box<MediaLocation>),
+    PostAlreadyExists,
+    QuotaExceeded {
+        limit_in_mb: u32,
+    },
+    StorageFull {
+        limit_in_mb: u32,
+    },
+    DiskSpaceLow,
+    FileTooLarge {
+        file_type: FileType,
+        limit_in_mb: u32,
+    },
+    UsernameNotSpecified,
+    AlbumNotFound,
+    NoData,
+    NoAlbums,
+    NoAlbumsGenerated,
+    InvalidInvite,
+    UserNotFound,
+    InvalidQuota,
+    UserBanned,
+    Io(io::Error),
+    Json(serde_json::Error),
+    Template(tera::Error),
+    Zip(zip::result::ZipError),
+    Telegram(RequestError),
+    Download(DownloadError),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Duplicate(original) => write!(
+                f,
+                "media already exists in album \"{}\" (post #{})",
+                original.album_username, original.post_id
+            ),
+            ArchiveError::PostAlreadyExists => write!(f, "post already exists"),
+            ArchiveError::QuotaExceeded { limit_in_mb } => {
+                write!(f, "user folder size limit of {} MB exceeded", limit_in_mb)
+            }
+            ArchiveError::StorageFull { limit_in_mb } => {
+                write!(f, "data folder size limit of {} MB exceeded", limit_in_mb)
+            }
+            ArchiveError::DiskSpaceLow => write!(f, "not enough free disk space"),
+            ArchiveError::FileTooLarge {
+                file_type,
+                limit_in_mb,
+            } => write!(
+                f,
+                "{} file size limit of {} MB exceeded",
+                file_type, limit_in_mb
+            ),
+            ArchiveError::UsernameNotSpecified => write!(f, "username is not specified"),
+            ArchiveError::AlbumNotFound => write!(f, "album not found"),
+            ArchiveError::NoData => write!(f, "no data found"),
+            ArchiveError::NoAlbums => write!(f, "no albums found"),
+            ArchiveError::NoAlbumsGenerated => write!(f, "no albums have been generated"),
+            ArchiveError::InvalidInvite => write!(f, "invite code is invalid or expired"),
+            ArchiveError::UserNotFound => write!(f, "user not found"),
+            ArchiveError::InvalidQuota => write!(f, "folder size limit must be greater than 0 MB"),
+            ArchiveError::UserBanned => write!(f, "user is banned"),
+            ArchiveError::Io(e) => write!(f, "I/O error: {}", e),
+            ArchiveError::Json(e) => write!(f, "JSON error: {}", e),
+            ArchiveError::Template(e) => write!(f, "template error: {}", e),
+            ArchiveError::Zip(e) => write!(f, "zip error: {}", e),
+            ArchiveError::Telegram(e) => write!(f, "Telegram request error: {}", e),
+            ArchiveError::Download(e) => write!(f, "error downloading media file: {}", e),
+        }
+    }
+}
+
+impl Error for ArchiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ArchiveError::Io(e) => Some(e),
+            ArchiveError::Json(e) => Some(e),
+            ArchiveError::Template(e) => Some(e),
+            ArchiveError::Zip(e) => Some(e),
+            ArchiveError::Telegram(e) => Some(e),
+            ArchiveError::Download(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(e: io::Error) -> Self {
+        ArchiveError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(e: serde_json::Error) -> Self {
+        ArchiveError::Json(e)
+    }
+}
+
+impl From<tera::Error> for ArchiveError {
+    fn from(e: tera::Error) -> Self {
+        ArchiveError::Template(e)
+    }
+}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ArchiveError::Zip(e)
+    }
+}
+
+impl From<RequestError> for ArchiveError {
+    fn from(e: RequestError) -> Self {
+        ArchiveError::Telegram(e)
+    }
+}
+
+impl From<DownloadError> for ArchiveError {
+    fn from(e: DownloadError) -> Self {
+        ArchiveError::Download(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PhotoLocation {
+    pub album_username: String,
+    pub post_id: i32,
+    pub file_name: String,
+    phash: u64,
+}
+
+/// Settings of a generation job which can differ from one job to another
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveOptions {
+    pub compression: Compression,
+    /// Size in bytes above which the archive is split into several volumes
+    pub max_volume_size: u64,
+    /// Number of posts on each album page, 0 to put all posts on one page
+    pub posts_per_page: usize,
+}
+
+/// Result of a generation job. Its workspace, including the archive, is deleted when this is dropped
+pub struct GeneratedArchive {
+    pub album_count: u64,
+    /// Zip files which can each be opened on their own, a single one if the archive fits into one volume
+    pub volumes: Vec<PathBuf>,
+    /// Description of albums in each volume, only present if the archive is split
+    pub index_path: Option<PathBuf>,
+    workspace: TempDir,
+}
+
+impl GeneratedArchive {
+    /// Moves the archive files out of the workspace into `result_folder`, so that they outlive the job
+    pub fn keep(&self, result_folder: &str, user_id: u64) -> Result<Vec<PathBuf>, ArchiveError> {
+        let user_folder = Path::new(result_folder).join(user_id.to_string());
+        fs::create_dir_all(&user_folder)?;
+
+        let mut kept_paths = Vec::new();
+        for file_path in self.index_path.iter().chain(self.volumes.iter()) {
+            // Archive names only have second resolution, prepend the unique workspace name
+            let file_name = format!(
+                "{}-{}",
+                self.workspace
+                    .path()
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                file_path.file_name().unwrap_or_default().to_string_lossy()
+            );
+            let kept_path = user_folder.join(file_name);
+            fs::rename(file_path, &kept_path)?;
+            kept_paths.push(kept_path);
+        }
+
+        Ok(kept_paths)
+    }
+}
+
+/// Space reserved in each volume for the end of the zip file and a possible growth of compressed text
+const VOLUME_SIZE_MARGIN: u64 = 1024 * 1024;
+
+/// Approximate size of the zip headers of a single file, besides its name
+const ZIP_ENTRY_OVERHEAD: u64 = 128;
+
+struct ArchiveFile {
+    path: PathBuf,
+    name: String,
+    size: u64,
+}
+
+impl ArchiveFile {
+    fn zipped_size(&self) -> u64 {
+        // The name is stored both in the local header and in the central directory
+        self.size + ZIP_ENTRY_OVERHEAD + 2 * self.name.len() as u64
+    }
+}
+
+/// Files of a single archive volume
+#[derive(Default)]
+struct Volume {
+    files: Vec<(PathBuf, String)>,
+    size: u64,
+    // Album folders in the volume with the number of their media files
+    albums: Vec<(String, usize)>,
+}
+
+impl Volume {
+    fn contains_album(&self, album: &str) -> bool {
+        self.albums.last().is_some_and(|(name, _)| name == album)
+    }
+
+    fn add(&mut self, file: &ArchiveFile) {
+        self.size += file.zipped_size();
+        self.files.push((file.path.clone(), file.name.clone()));
+    }
+}
+
 #[derive(Debug)]
 pub struct ChannelInfo {
     pub channel: TelegramChannel,
     pub user_folder_size_in_mb: f64,
 }
 
+/// Occupied space of an album in bytes, split by media type
+#[derive(Debug)]
+pub struct AlbumUsage {
+    pub username: String,
+    pub photos_size: u64,
+    pub videos_size: u64,
+    pub total_size: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TelegramData {
     channels: Vec<TelegramChannel>,
+    /// Theme of all albums which don't have their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    theme: Option<String>,
+}
+
+impl TelegramData {
+    fn get_media_locations(&self) -> Vec<MediaLocation> {
+        let mut locations = Vec::new();
+        for channel in self.channels.iter() {
+            for post in channel.posts.iter() {
+                for media in post.media.iter() {
+                    locations.push(MediaLocation {
+                        album_username: channel.username.clone(),
+                        album_title: channel.title.clone(),
+                        post_id: post.id,
+                        media: media.clone(),
+                    });
+                }
+            }
+        }
+
+        locations
+    }
+
+    /// Adds records with content hashes for media of posts archived before duplicate detection,
+    /// so that their media is matched as well. Returns whether any record has been added
+    fn add_missing_media_records(&mut self, user_folder: &Path) -> bool {
+        let mut records_added = false;
+        for channel in &mut self.channels {
+            let album_path = user_folder.join(&channel.username);
+            for post in &mut channel.posts {
+                for file_name in post.photos.iter().chain(post.videos.iter()) {
+                    if post.media.iter().any(|media| &media.file_name == file_name) {
+                        continue;
+                    }
+
+                    // Files removed from the album can never be hashed, so they are not retried on every post
+                    let file_path = album_path.join(file_name);
+                    if !file_path.exists() {
+                        continue;
+                    }
+
+                    match get_file_hash(&file_path) {
+                        Ok(sha256) => {
+                            // The unique ID is unknown for these files, so they are only matched by contents
+                            post.media.push(MediaRecord {
+                                file_name: file_name.clone(),
+                                file_unique_id: String::new(),
+                                sha256,
+                                phash: None,
+                            });
+                            records_added = true;
+                        }
+                        Err(e) => warn!(
+                            "Unable to compute hash for \"{}\" in album \"{}\": {}",
+                            file_name, channel.username, e
+                        ),
+                    }
+                }
+            }
+        }
+
+        records_added
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -48,6 +371,8 @@ pub struct TelegramChannel {
     description: String,
     username: String,
     posts: Vec<TelegramPost>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    theme: Option<String>,
 }
 
 impl TelegramChannel {
@@ -72,22 +397,55 @@ struct TelegramPost {
     text: String,
     photos: Vec<String>,
     videos: Vec<String>,
+    #[serde(default)]
+    media: Vec<MediaRecord>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct MediaRecord {
+    file_name: String,
+    file_unique_id: String,
+    sha256: String,
+    #[serde(default)]
+    phash: Option<u64>,
+}
+
+/// Storage limits checked before a new media file is downloaded
+pub struct StorageLimits {
+    pub max_user_folder_size_in_mb: u32,
+    pub max_data_folder_size_in_mb: Option<u32>,
+    pub min_free_disk_space_in_mb: u32,
+    pub max_photo_size_in_mb: u32,
+    pub max_video_size_in_mb: u32,
+}
+
+/// Space already in use in bytes, taken from the usage index
+pub struct StorageUsage {
+    pub user_folder_size: u64,
+    pub data_folder_size: u64,
+}
+
+struct NewMediaOptions<'a> {
+    user_folder: &'a Path,
+    album_path: &'a Path,
+    limits: &'a StorageLimits,
+    usage: &'a StorageUsage,
+    free_disk_space: u64,
+    known_media: &'a [MediaLocation],
+    duplicate_policy: DuplicatePolicy,
+    perceptual_hashing: bool,
 }
 
 impl TelegramPost {
+    /// Downloads media of the message and returns the size of newly stored files in bytes.
+    /// Duplicates linked to an existing file take no additional space and are not counted
     async fn add_media(
         &mut self,
         bot: Bot,
         msg: Message,
-        album_path: &Path,
-        user_folder_size: u32,
-        max_user_folder_size_in_mb: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        // Photo file size shouldn't exceed 5 MB and
-        // video file size shouldn't exceed 20 MB as stated in
-        // https://core.telegram.org/bots/api#sending-files
-        const MAX_PHOTO_FIZE_SIZE_IN_MB: u32 = 5;
-        const MAX_VIDEO_FIZE_SIZE_IN_MB: u32 = 20;
+        options: &NewMediaOptions<'_>,
+    ) -> Result<u64, ArchiveError> {
+        let mut stored_size = 0;
 
         // Proceed if there is only one photo
         if let Some(photos) = msg.photo() {
@@ -102,17 +460,15 @@ impl TelegramPost {
                     FileType::Photo,
                     &photo.file.id,
                     photo.file.size,
-                    MAX_PHOTO_FIZE_SIZE_IN_MB,
-                    user_folder_size,
-                    max_user_folder_size_in_mb,
+                    options.limits.max_photo_size_in_mb,
+                    options,
                 )?;
 
-                match download_media_file(bot, album_path, &photo.file.id, "jpg").await {
-                    Ok(file_name) => {
-                        self.photos.push(file_name.to_string());
-                    }
-                    Err(_) => return Err("error downloading media file".into()),
-                }
+                let (file_name, size) = self
+                    .store_media_file(bot, FileType::Photo, &photo.file, "jpg", options)
+                    .await?;
+                self.photos.push(file_name);
+                stored_size += size;
             }
         } else if let Some(video) = msg.video() {
             // Set post caption
@@ -126,67 +482,226 @@ impl TelegramPost {
                         FileType::Video,
                         &video.file.id,
                         video.file.size,
-                        MAX_VIDEO_FIZE_SIZE_IN_MB,
-                        user_folder_size,
-                        max_user_folder_size_in_mb,
+                        options.limits.max_video_size_in_mb,
+                        options,
                     )?;
 
-                    match download_media_file(bot, album_path, &video.file.id, "mp4").await {
-                        Ok(file_name) => {
-                            self.videos.push(file_name.to_string());
-                        }
-                        Err(_) => return Err("error downloading media file".into()),
-                    }
+                    let (file_name, size) = self
+                        .store_media_file(bot, FileType::Video, &video.file, "mp4", options)
+                        .await?;
+                    self.videos.push(file_name);
+                    stored_size += size;
                 }
             }
         }
 
-        Ok(())
+        Ok(stored_size)
+    }
+
+    /// Stores a media file and returns its name with the number of bytes it added to the user folder
+    async fn store_media_file(
+        &mut self,
+        bot: Bot,
+        file_type: FileType,
+        file: &FileMeta,
+        file_extension: &str,
+        options: &NewMediaOptions<'_>,
+    ) -> Result<(String, u64), ArchiveError> {
+        // Telegram keeps the same unique ID for a file reposted by different channels,
+        // so most duplicates can be detected without downloading anything
+        if let Some(original) = options
+            .known_media
+            .iter()
+            .find(|location| location.media.file_unique_id == file.unique_id)
+        {
+            match options.duplicate_policy {
+                DuplicatePolicy::Reject => {
+                    return Err(ArchiveError::Duplicate(Box::new(original.clone())))
+                }
+                DuplicatePolicy::Link => {
+                    let file_name = format!("{}.{}", file.id, file_extension);
+                    link_media_file(options, original, &file_name)?;
+                    self.media.push(MediaRecord {
+                        file_name: file_name.clone(),
+                        file_unique_id: file.unique_id.clone(),
+                        sha256: original.media.sha256.clone(),
+                        phash: original.media.phash,
+                    });
+                    return Ok((file_name, 0));
+                }
+                DuplicatePolicy::Copy => {}
+            }
+        }
+
+        let file_name =
+            download_media_file(bot, options.album_path, &file.id, file_extension).await?;
+
+        // Media re-uploaded by another channel gets a new unique ID, compare the contents instead
+        let file_path = options.album_path.join(&file_name);
+        let sha256 = {
+            let file_path = file_path.clone();
+            run_blocking(move || Ok(get_file_hash(&file_path)?)).await?
+        };
+        let mut linked = false;
+        if let Some(original) = options
+            .known_media
+            .iter()
+            .find(|location| location.media.sha256 == sha256)
+        {
+            match options.duplicate_policy {
+                DuplicatePolicy::Reject => {
+                    fs::remove_file(&file_path)?;
+                    return Err(ArchiveError::Duplicate(Box::new(original.clone())));
+                }
+                DuplicatePolicy::Link => {
+                    fs::remove_file(&file_path)?;
+                    link_media_file(options, original, &file_name)?;
+                    linked = true;
+                }
+                DuplicatePolicy::Copy => {}
+            }
+        }
+
+        let mut phash = None;
+        if options.perceptual_hashing && matches!(file_type, FileType::Photo) {
+            let photo_path = file_path.clone();
+            match run_blocking(move || Ok(get_perceptual_hash(&photo_path))).await? {
+                Ok(hash) => phash = Some(hash),
+                Err(e) => warn!(
+                    "Unable to compute perceptual hash for \"{}\": {}",
+                    file_path.display(),
+                    e
+                ),
+            }
+        }
+
+        self.media.push(MediaRecord {
+            file_name: file_name.clone(),
+            file_unique_id: file.unique_id.clone(),
+            sha256,
+            phash,
+        });
+
+        let stored_size = if linked {
+            0
+        } else {
+            fs::metadata(&file_path)?.len()
+        };
+        Ok((file_name, stored_size))
     }
 }
 
+fn link_media_file(
+    options: &NewMediaOptions<'_>,
+    original: &MediaLocation,
+    file_name: &str,
+) -> Result<(), ArchiveError> {
+    let src = options
+        .user_folder
+        .join(&original.album_username)
+        .join(&original.media.file_name);
+    let dst = options.album_path.join(file_name);
+
+    // The same file may already be stored under this name in the current album
+    if src != dst {
+        fs::create_dir_all(options.album_path)?;
+        link_or_copy_file(&src, &dst)?;
+    }
+
+    Ok(())
+}
+
 fn check_sizes(
     user_id: u64,
     file_type: FileType,
     file_id: &String,
     file_size: u32,
     max_file_size_in_mb: u32,
-    user_folder_size: u32,
-    max_user_folder_size_in_mb: u32,
-) -> Result<(), Box<dyn Error>> {
+    options: &NewMediaOptions,
+) -> Result<(), ArchiveError> {
+    let limits = options.limits;
+    let usage = options.usage;
+    let file_size = file_size as u64;
+
     // Convert megabytes into bytes
-    let max_user_folder_size = max_user_folder_size_in_mb * 1024 * 1024;
-    let max_file_size: u32 = max_file_size_in_mb * 1024 * 1024;
+    let max_user_folder_size = limits.max_user_folder_size_in_mb as u64 * 1024 * 1024;
+    let max_file_size = max_file_size_in_mb as u64 * 1024 * 1024;
     if file_size > max_file_size {
         error!(
             "Cannot get {} file \"{}\" as it exceeds the size limit: {} > {}",
             file_type, file_id, file_size, max_file_size
         );
-        return Err(format!(
-            "{} file size exceeds {} MB size limit!",
-            file_type.to_string().remove(0).to_uppercase().to_string()
-                + &file_type.to_string()[1..],
-            max_file_size_in_mb
-        )
-        .into());
+        return Err(ArchiveError::FileTooLarge {
+            file_type,
+            limit_in_mb: max_file_size_in_mb,
+        });
     }
 
-    let new_user_folder_size = file_size + user_folder_size;
+    let new_user_folder_size = file_size + usage.user_folder_size;
     if new_user_folder_size > max_user_folder_size {
         error!(
             "User #{} folder cannot exceed the size limit: {} > {}",
             user_id, new_user_folder_size, max_user_folder_size
         );
-        return Err(format!(
-            "User folder cannot exceed {} MB size limit!",
-            max_user_folder_size_in_mb
-        )
-        .into());
+        return Err(ArchiveError::QuotaExceeded {
+            limit_in_mb: limits.max_user_folder_size_in_mb,
+        });
+    }
+
+    if let Some(max_data_folder_size_in_mb) = limits.max_data_folder_size_in_mb {
+        let max_data_folder_size = max_data_folder_size_in_mb as u64 * 1024 * 1024;
+        let new_data_folder_size = file_size + usage.data_folder_size;
+        if new_data_folder_size > max_data_folder_size {
+            error!(
+                "Data folder cannot exceed the size limit: {} > {}",
+                new_data_folder_size, max_data_folder_size
+            );
+            return Err(ArchiveError::StorageFull {
+                limit_in_mb: max_data_folder_size_in_mb,
+            });
+        }
+    }
+
+    // Keep some disk space free for generated albums and other applications
+    let min_free_disk_space = limits.min_free_disk_space_in_mb as u64 * 1024 * 1024;
+    let new_free_disk_space = options.free_disk_space.saturating_sub(file_size);
+    if new_free_disk_space < min_free_disk_space {
+        error!(
+            "Not enough free disk space to store {} file \"{}\": {} < {}",
+            file_type, file_id, new_free_disk_space, min_free_disk_space
+        );
+        return Err(ArchiveError::DiskSpaceLow);
     }
 
     Ok(())
 }
 
+async fn read_telegram_data(file_path: &Path) -> Result<TelegramData, ArchiveError> {
+    if !file_path.exists() {
+        return Err(ArchiveError::NoData);
+    }
+
+    let json_data = tokio::fs::read_to_string(file_path).await?;
+    Ok(serde_json::from_str(&json_data)?)
+}
+
+async fn write_telegram_data(file_path: &Path, data: &TelegramData) -> Result<(), ArchiveError> {
+    let json_data = serde_json::to_string_pretty(data)?;
+    tokio::fs::write(file_path, json_data).await?;
+    Ok(())
+}
+
+/// Runs blocking filesystem work on a dedicated thread, so that other users' updates keep being handled
+async fn run_blocking<T, F>(f: F) -> Result<T, ArchiveError>
+where
+    F: FnOnce() -> Result<T, ArchiveError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ArchiveError::Io(io::Error::other(e)))?
+}
+
 fn parse_date(date_str: &str) -> DateTime<Utc> {
     let naive_date = NaiveDateTime::parse_from_str(date_str, "%Y-%m-%d %H:%M:%S UTC").unwrap();
     DateTime::<Utc>::from_naive_utc_and_offset(naive_date, Utc)
@@ -197,29 +712,62 @@ fn round_to_nearest_minute(date: DateTime<Utc>) -> DateTime<Utc> {
     date - chrono::Duration::seconds(seconds)
 }
 
-async fn create_html_file(
-    album_folder: &Path,
-    src_media_folder: &PathBuf,
-    data: &str,
-) -> Result<(), Box<dyn Error>> {
-    let src_css = Path::new("templates").join("css");
-    let src_img = Path::new("templates").join("img");
+/// Name of the file of an album page, the first page being the album's `index.html`
+fn get_page_file_name(page: usize) -> String {
+    if page == 1 {
+        "index.html".to_string()
+    } else {
+        format!("page-{}.html", page)
+    }
+}
 
-    let dst_css = album_folder.join("css");
-    let dst_img = album_folder.join("img");
-    let dst_media_folder = album_folder.join("gallery");
+/// Links between the pages of an album, passed to the templates as `pagination`
+#[derive(Serialize)]
+struct Pagination {
+    /// Number of the current page, starting from 1
+    current: usize,
+    page_count: usize,
+    /// File names of all pages in order
+    pages: Vec<String>,
+    previous: Option<String>,
+    next: Option<String>,
+}
 
-    // Copy the 'css' and 'img' folder
-    copy_dir_all(&src_css, &dst_css)?;
-    copy_dir_all(&src_img, &dst_img)?;
+/// Writes the pages of an album together with the assets of its theme. `files` are written
+/// next to the pages and replace all files left there by a previous render
+async fn create_html_files(
+    album_folder: &Path,
+    theme: &Theme,
+    files: Vec<(String, String)>,
+) -> Result<(), ArchiveError> {
+    let assets = theme.assets.clone();
+    let album_folder = album_folder.to_path_buf();
+
+    run_blocking(move || {
+        // Write the 'css' and 'img' folder
+        write_assets(&assets, &album_folder)?;
+
+        // Media is zipped straight from the user folder and never staged next to the page
+        let media_folder = album_folder.join("gallery");
+        if media_folder.exists() {
+            fs::remove_dir_all(&media_folder)?;
+        }
 
-    // Copy the media folder
-    copy_dir_all(src_media_folder, &dst_media_folder)?;
+        // Remove pages of the previous render, which may have had more pages
+        for entry in fs::read_dir(&album_folder)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::remove_file(entry.path())?;
+            }
+        }
 
-    let file_name = album_folder.join("index.html");
-    fs::write(file_name, data)?;
+        for (file_name, data) in files {
+            fs::write(album_folder.join(file_name), data)?;
+        }
 
-    Ok(())
+        Ok(())
+    })
+    .await
 }
 
 async fn download_media_file(
@@ -227,35 +775,72 @@ async fn download_media_file(
     album_path: &Path,
     file_id: &String,
     file_extension: &str,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<String, ArchiveError> {
     let file_name = format!("{}.{}", file_id, file_extension);
     let file = bot.get_file(file_id).await?;
     fs::create_dir_all(album_path)?;
-    let mut dst = FileAsync::create(&album_path.join(&file_name)).await?;
-    bot.download_file(&file.path, &mut dst).await?;
+    let dst_path = album_path.join(&file_name);
+
+    // A Bot API server in local mode returns an absolute path to the file on its own disk
+    // instead of a path to download it from
+    let src_path = PathBuf::from(&file.path);
+    if src_path.is_absolute() {
+        run_blocking(move || Ok(link_or_copy_file(&src_path, &dst_path)?)).await?;
+    } else {
+        let mut dst = FileAsync::create(&dst_path).await?;
+        bot.download_file(&file.path, &mut dst).await?;
+    }
 
     Ok(file_name)
 }
 
+pub fn get_user_folder_ids(data_folder: &str) -> Result<Vec<u64>, ArchiveError> {
+    let mut user_ids = Vec::new();
+
+    // User folders are named after user IDs, skip everything else
+    for entry in fs::read_dir(data_folder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            if let Some(user_id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<u64>().ok())
+            {
+                user_ids.push(user_id);
+            }
+        }
+    }
+
+    Ok(user_ids)
+}
+
 pub async fn delete_user_folders(
     user_id: u64,
     data_folder: &str,
-) -> Result<String, Box<dyn Error>> {
+    result_folder: &str,
+) -> Result<String, ArchiveError> {
     let user_folder = Path::new(data_folder).join(user_id.to_string());
 
+    // Rendered albums are useless without the data they were rendered from
+    let cache_folder = get_render_cache_folder(result_folder, user_id);
+    if cache_folder.exists() {
+        let _cache_lock = lock_render_cache(user_id).await;
+        tokio::fs::remove_dir_all(cache_folder).await?;
+    }
+
     if !user_folder.exists() {
         error!("No user data found for user #{}.", user_id);
-        return Err("No data found!".into());
+        return Err(ArchiveError::NoData);
     }
 
     // Attempt to remove the specified folder and its contents
-    match fs::remove_dir_all(user_folder) {
+    match tokio::fs::remove_dir_all(user_folder).await {
         Ok(_) => {
             info!("All user data for user #{} successfully deleted.", user_id);
         }
         Err(e) => {
             error!("Error deleting user data for user #{}: {}", user_id, e);
-            return Err("error deleting data folder".into());
+            return Err(e.into());
         }
     }
 
@@ -266,9 +851,9 @@ pub async fn delete_user_album(
     username: String,
     user_id: u64,
     data_folder: &str,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<String, ArchiveError> {
     if username.is_empty() {
-        return Err("username is not specified".into());
+        return Err(ArchiveError::UsernameNotSpecified);
     }
 
     let file_path = Path::new(data_folder)
@@ -276,11 +861,7 @@ pub async fn delete_user_album(
         .join("data.json");
 
     // Read the file contents
-    let mut file = File::open(&file_path)?;
-    let mut json_data = String::new();
-    file.read_to_string(&mut json_data)?;
-
-    let mut telegram_data: TelegramData = serde_json::from_str(&json_data)?;
+    let mut telegram_data = read_telegram_data(&file_path).await?;
 
     if let Some(index) = telegram_data
         .channels
@@ -290,11 +871,10 @@ pub async fn delete_user_album(
         telegram_data.channels.remove(index);
     } else {
         error!("Album \"{}\" not found for user #{}", username, user_id);
-        return Ok("Album not found.".to_string());
+        return Err(ArchiveError::AlbumNotFound);
     }
 
-    let updated_telegram_data = serde_json::to_string_pretty(&telegram_data)?;
-    fs::write(&file_path, updated_telegram_data)?;
+    write_telegram_data(&file_path, &telegram_data).await?;
     info!(
         "Album \"{}\" for user #{} successfully deleted from JSON file.",
         username, user_id
@@ -306,7 +886,7 @@ pub async fn delete_user_album(
 
     if album_folder.exists() {
         // Attempt to remove the specified folder and its contents
-        match fs::remove_dir_all(album_folder) {
+        match tokio::fs::remove_dir_all(album_folder).await {
             Ok(_) => {
                 info!(
                     "Album \"{}\" for user #{} successfully deleted.",
@@ -318,7 +898,7 @@ pub async fn delete_user_album(
                     "Error deleting album \"{}\" for user #{}: {}",
                     username, user_id, e
                 );
-                return Err("error deleting album folder".into());
+                return Err(e.into());
             }
         }
     }
@@ -326,53 +906,165 @@ pub async fn delete_user_album(
     Ok("Album deleted.".to_string())
 }
 
+/// Sets the theme of an album, or of all albums without their own theme if `username` is `None`.
+/// A theme of `None` makes them use the user's theme or the default theme again
+pub async fn set_theme(
+    user_id: u64,
+    data_folder: &str,
+    username: Option<String>,
+    theme: Option<String>,
+) -> Result<String, ArchiveError> {
+    let file_path = Path::new(data_folder)
+        .join(user_id.to_string())
+        .join("data.json");
+    let mut telegram_data = read_telegram_data(&file_path).await?;
+
+    let message = match &username {
+        Some(username) => {
+            let Some(channel) = telegram_data
+                .channels
+                .iter_mut()
+                .find(|channel| &channel.username == username)
+            else {
+                error!("Album \"{}\" not found for user #{}", username, user_id);
+                return Err(ArchiveError::AlbumNotFound);
+            };
+            channel.theme = theme.clone();
+            match &theme {
+                Some(theme) => format!("Album \"{}\" now uses theme \"{}\".", username, theme),
+                None => format!("Album \"{}\" now uses your default theme.", username),
+            }
+        }
+        None => {
+            telegram_data.theme = theme.clone();
+            match &theme {
+                Some(theme) => format!("Your albums now use theme \"{}\".", theme),
+                None => "Your albums now use the default theme.".to_string(),
+            }
+        }
+    };
+
+    write_telegram_data(&file_path, &telegram_data).await?;
+    info!(
+        "Theme of {} of user #{} set to {:?}.",
+        username.map_or("all albums".to_string(), |username| format!(
+            "album \"{}\"",
+            username
+        )),
+        user_id,
+        theme
+    );
+
+    Ok(message)
+}
+
+/// Returns the theme chosen by the user and the albums which have their own
+pub async fn get_themes(
+    user_id: u64,
+    data_folder: &str,
+) -> Result<(Option<String>, Vec<(String, String)>), ArchiveError> {
+    let file_path = Path::new(data_folder)
+        .join(user_id.to_string())
+        .join("data.json");
+    let telegram_data = read_telegram_data(&file_path).await?;
+
+    let album_themes = telegram_data
+        .channels
+        .into_iter()
+        .filter_map(|channel| channel.theme.map(|theme| (channel.username, theme)))
+        .collect();
+
+    Ok((telegram_data.theme, album_themes))
+}
+
 pub async fn get_album_descriptions(
     user_id: u64,
     data_folder: &str,
-) -> Result<Vec<ChannelInfo>, Box<dyn Error>> {
+) -> Result<Vec<ChannelInfo>, ArchiveError> {
     // Read the file contents
     let file_path = Path::new(data_folder)
         .join(user_id.to_string())
         .join("data.json");
-    let mut file = File::open(&file_path)?;
-    let mut json_data = String::new();
-    file.read_to_string(&mut json_data)?;
-
-    let telegram_data: TelegramData = serde_json::from_str(&json_data)?;
-
-    let mut channels_list: Vec<ChannelInfo> = Vec::new();
-    for channel in telegram_data.channels.iter() {
-        let channel_folder = Path::new(data_folder)
-            .join(user_id.to_string())
-            .join(&channel.username);
-        let user_folder_size_in_mb = convert_to_mb(get_folder_size(&channel_folder));
-        let channel_info = ChannelInfo {
-            channel: channel.clone(),
-            user_folder_size_in_mb,
-        };
-        channels_list.push(channel_info);
-    }
+    let telegram_data = read_telegram_data(&file_path).await?;
+
+    let user_folder = Path::new(data_folder).join(user_id.to_string());
+    let channels_list = run_blocking(move || {
+        let mut channels_list: Vec<ChannelInfo> = Vec::new();
+        for channel in telegram_data.channels.into_iter() {
+            let channel_folder = user_folder.join(&channel.username);
+            let user_folder_size_in_mb = convert_to_mb(get_folder_size(&channel_folder));
+            let channel_info = ChannelInfo {
+                channel,
+                user_folder_size_in_mb,
+            };
+            channels_list.push(channel_info);
+        }
+
+        Ok(channels_list)
+    })
+    .await?;
 
     if channels_list.is_empty() {
-        return Err("no albums found".into());
+        return Err(ArchiveError::NoAlbums);
     }
 
     Ok(channels_list)
 }
 
-pub async fn consolidate_media(user_id: u64, data_folder: &str) -> Result<String, Box<dyn Error>> {
+pub async fn get_album_usage(
+    user_id: u64,
+    data_folder: &str,
+) -> Result<Vec<AlbumUsage>, ArchiveError> {
+    let user_folder = Path::new(data_folder).join(user_id.to_string());
+    let telegram_data = read_telegram_data(&user_folder.join("data.json")).await?;
+
+    let mut albums = run_blocking(move || {
+        let mut albums: Vec<AlbumUsage> = Vec::new();
+        for channel in telegram_data.channels.iter() {
+            let album_folder = user_folder.join(&channel.username);
+            let files_size = |file_names: &mut dyn Iterator<Item = &String>| -> u64 {
+                file_names
+                    .map(|file_name| {
+                        fs::metadata(album_folder.join(file_name)).map_or(0, |m| m.len())
+                    })
+                    .sum()
+            };
+
+            albums.push(AlbumUsage {
+                username: channel.username.clone(),
+                photos_size: files_size(
+                    &mut channel.posts.iter().flat_map(|post| post.photos.iter()),
+                ),
+                videos_size: files_size(
+                    &mut channel.posts.iter().flat_map(|post| post.videos.iter()),
+                ),
+                total_size: get_folder_size(&album_folder),
+            });
+        }
+
+        Ok(albums)
+    })
+    .await?;
+
+    if albums.is_empty() {
+        return Err(ArchiveError::NoAlbums);
+    }
+
+    // The largest albums come first as the best candidates for pruning
+    albums.sort_by_key(|album| Reverse(album.total_size));
+
+    Ok(albums)
+}
+
+pub async fn consolidate_media(user_id: u64, data_folder: &str) -> Result<String, ArchiveError> {
     // Read the file contents
     let file_path = Path::new(data_folder)
         .join(user_id.to_string())
         .join("data.json");
-    let mut file = File::open(&file_path)?;
-    let mut json_data = String::new();
-    file.read_to_string(&mut json_data)?;
-
-    let mut telegram_data: TelegramData = serde_json::from_str(&json_data)?;
+    let mut telegram_data = read_telegram_data(&file_path).await?;
 
     if telegram_data.channels.is_empty() {
-        return Err("no albums found".into());
+        return Err(ArchiveError::NoAlbums);
     }
 
     // Consolidate posts with the same date and forward_date
@@ -390,20 +1082,21 @@ pub async fn consolidate_media(user_id: u64, data_folder: &str) -> Result<String
 
             similar_posts
                 .entry((date_rounded, forward_date_rounded))
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(post);
         }
 
         // Replace posts with consolidated ones
         let mut updated_posts: Vec<TelegramPost> = similar_posts
-            .into_iter()
-            .flat_map(|(_, posts)| {
+            .into_values()
+            .flat_map(|posts| {
                 posts.into_iter().fold(
                     None,
                     |acc: Option<TelegramPost>, post: &mut TelegramPost| match acc {
                         Some(mut updated_post) => {
                             updated_post.photos.extend_from_slice(&post.photos);
                             updated_post.videos.extend_from_slice(&post.videos);
+                            updated_post.media.extend_from_slice(&post.media);
                             if !post.text.is_empty() {
                                 updated_post.text = post.text.clone();
                             }
@@ -421,8 +1114,7 @@ pub async fn consolidate_media(user_id: u64, data_folder: &str) -> Result<String
         channel.posts = updated_posts;
     }
 
-    let updated_telegram_data = serde_json::to_string_pretty(&telegram_data)?;
-    fs::write(file_path, updated_telegram_data)?;
+    write_telegram_data(&file_path, &telegram_data).await?;
     info!(
         "Posts in all albums for user#{} have been successfully consolidated.",
         user_id
@@ -431,23 +1123,294 @@ pub async fn consolidate_media(user_id: u64, data_folder: &str) -> Result<String
     Ok("Posts in all albums have been successfully consolidated.".into())
 }
 
-async fn generate_single_album(
-    tera: &Tera,
-    channel: &TelegramChannel,
+pub async fn find_similar_photos(
+    username: String,
     user_id: u64,
     data_folder: &str,
-    result_folder: &str,
-) -> Result<(), Box<dyn Error>> {
-    let mut context = Context::new();
-    context.insert("channel", &channel);
-    let data = tera.render("content.html", &context)?;
-    let album_folder = Path::new(result_folder)
-        .join(user_id.to_string())
-        .join(&channel.username);
-    let src_media_folder = Path::new(data_folder)
+    similarity_threshold: u32,
+) -> Result<Vec<Vec<PhotoLocation>>, ArchiveError> {
+    // Read the file contents
+    let user_folder = Path::new(data_folder).join(user_id.to_string());
+    let file_path = user_folder.join("data.json");
+    let mut telegram_data = read_telegram_data(&file_path).await?;
+
+    if !username.is_empty()
+        && !telegram_data
+            .channels
+            .iter()
+            .any(|channel| channel.username == username)
+    {
+        return Err(ArchiveError::AlbumNotFound);
+    }
+
+    // Collect hashes of all photos, computing the missing ones for posts
+    // archived before perceptual hashing was enabled
+    let (telegram_data, photos, hashes_updated) = run_blocking(move || {
+        let mut photos: Vec<PhotoLocation> = Vec::new();
+        let mut hashes_updated = false;
+        for channel in &mut telegram_data.channels {
+            let album_path = user_folder.join(&channel.username);
+            for post in &mut channel.posts {
+                for file_name in post.photos.iter() {
+                    let record = post
+                        .media
+                        .iter_mut()
+                        .find(|media| &media.file_name == file_name);
+                    let phash = match record.as_ref().and_then(|media| media.phash) {
+                        Some(phash) => phash,
+                        None => match get_perceptual_hash(&album_path.join(file_name)) {
+                            Ok(phash) => {
+                                if let Some(media) = record {
+                                    media.phash = Some(phash);
+                                    hashes_updated = true;
+                                }
+                                phash
+                            }
+                            Err(e) => {
+                                warn!(
+                                    "Unable to compute perceptual hash for \"{}\" in album \"{}\" \
+                                     for user #{}: {}",
+                                    file_name, channel.username, user_id, e
+                                );
+                                continue;
+                            }
+                        },
+                    };
+
+                    photos.push(PhotoLocation {
+                        album_username: channel.username.clone(),
+                        post_id: post.id,
+                        file_name: file_name.clone(),
+                        phash,
+                    });
+                }
+            }
+        }
+
+        Ok((telegram_data, photos, hashes_updated))
+    })
+    .await?;
+
+    if hashes_updated {
+        write_telegram_data(&file_path, &telegram_data).await?;
+    }
+
+    // Group photos whose hashes differ by only a few bits
+    let mut groups: Vec<Vec<PhotoLocation>> = Vec::new();
+    for photo in photos {
+        match groups
+            .iter_mut()
+            .find(|group| (group[0].phash ^ photo.phash).count_ones() <= similarity_threshold)
+        {
+            Some(group) => group.push(photo),
+            None => groups.push(vec![photo]),
+        }
+    }
+
+    groups.retain(|group| {
+        group.len() > 1
+            && (username.is_empty() || group.iter().any(|p| p.album_username == username))
+    });
+
+    Ok(groups)
+}
+
+/// Name of the folder in `result_folder` keeping rendered albums between generations
+const RENDER_CACHE_FOLDER: &str = "cache";
+
+fn get_render_cache_folder(result_folder: &str, user_id: u64) -> PathBuf {
+    Path::new(result_folder)
+        .join(RENDER_CACHE_FOLDER)
         .join(user_id.to_string())
-        .join(&channel.username);
-    create_html_file(&album_folder, &src_media_folder, &data).await?;
+}
+
+type RenderCacheLocks = HashMap<u64, Arc<tokio::sync::Mutex<()>>>;
+
+static RENDER_CACHE_LOCKS: LazyLock<Mutex<RenderCacheLocks>> = LazyLock::new(Default::default);
+
+fn get_render_cache_locks() -> MutexGuard<'static, RenderCacheLocks> {
+    RENDER_CACHE_LOCKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Exclusive access to the render cache of a user. The lock of the user is forgotten
+/// once no other job holds or waits for it, so that locks don't pile up for every user ever seen
+struct RenderCacheLock {
+    user_id: u64,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for RenderCacheLock {
+    fn drop(&mut self) {
+        // Release the lock first, so that only the map holds it if nobody else is waiting
+        self.guard.take();
+
+        let mut locks = get_render_cache_locks();
+        if locks
+            .get(&self.user_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.user_id);
+        }
+    }
+}
+
+/// Jobs of the same user share the render cache, so they have to take turns updating it
+async fn lock_render_cache(user_id: u64) -> RenderCacheLock {
+    let lock = get_render_cache_locks().entry(user_id).or_default().clone();
+
+    RenderCacheLock {
+        user_id,
+        guard: Some(lock.lock_owned().await),
+    }
+}
+
+/// Version of the layout of cached albums, bumped to render all albums again after it changes
+const RENDER_CACHE_VERSION: u32 = 3;
+
+/// Hashes everything a rendered album depends on: its posts and media names, the template and the page size
+fn get_album_hash(
+    channel: &TelegramChannel,
+    template_hash: &str,
+    posts_per_page: usize,
+) -> Result<String, ArchiveError> {
+    let mut hasher = Sha256::new();
+    hasher.update(RENDER_CACHE_VERSION.to_le_bytes());
+    hasher.update(template_hash.as_bytes());
+    hasher.update((posts_per_page as u64).to_le_bytes());
+    hasher.update(serde_json::to_vec(channel)?);
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+struct AlbumRenderer<'a> {
+    templates: &'a Templates,
+    /// Theme chosen by the user for albums without their own
+    user_theme: Option<&'a str>,
+    posts_per_page: usize,
+    cache_folder: PathBuf,
+    albums_folder: &'a Path,
+}
+
+impl AlbumRenderer<'_> {
+    /// Renders an album into the render cache unless it hasn't changed since the last generation,
+    /// then links the cached album into the workspace
+    async fn render(&self, channel: &TelegramChannel) -> Result<(), ArchiveError> {
+        let cached_album = self.cache_folder.join(&channel.username);
+        let hash_file = self.cache_folder.join(format!("{}.hash", channel.username));
+        let theme = self
+            .templates
+            .get_theme(channel.theme.as_deref().or(self.user_theme));
+        let hash = get_album_hash(channel, &theme.hash, self.posts_per_page)?;
+
+        let cached_hash = tokio::fs::read_to_string(&hash_file)
+            .await
+            .unwrap_or_default();
+        if cached_hash != hash || !cached_album.is_dir() {
+            let files = self.render_pages(channel, theme)?;
+
+            // Forget the previous hash first, so that an interrupted render is never taken as up to date
+            if hash_file.exists() {
+                tokio::fs::remove_file(&hash_file).await?;
+            }
+            create_html_files(&cached_album, theme, files).await?;
+            tokio::fs::write(&hash_file, hash).await?;
+        } else {
+            debug!(
+                "Album \"{}\" hasn't changed, reusing its previous render",
+                channel.username
+            );
+        }
+
+        // Link the cached pages into the workspace, so that other jobs can update the cache while this one is zipping
+        let album_folder = self.albums_folder.join(&channel.username);
+        run_blocking(move || Ok(sync_dir(&cached_album, &album_folder)?)).await
+    }
+
+    /// Renders the pages of an album, each with up to `posts_per_page` posts. Albums with several pages
+    /// also get `posts.js`, which maps post IDs to pages, so that permalinks of the form
+    /// `index.html#post-<ID>` lead to the page holding the post
+    fn render_pages(
+        &self,
+        channel: &TelegramChannel,
+        theme: &Theme,
+    ) -> Result<Vec<(String, String)>, ArchiveError> {
+        let mut page_posts: Vec<&[TelegramPost]> = if self.posts_per_page == 0 {
+            vec![&channel.posts]
+        } else {
+            channel.posts.chunks(self.posts_per_page).collect()
+        };
+        // An album without posts still gets its index page
+        if page_posts.is_empty() {
+            page_posts.push(&[]);
+        }
+
+        let page_count = page_posts.len();
+        let pages: Vec<String> = (1..=page_count).map(get_page_file_name).collect();
+        let mut files = Vec::with_capacity(page_count + 1);
+
+        for (index, posts) in page_posts.iter().enumerate() {
+            let page_channel = TelegramChannel {
+                id: channel.id,
+                title: channel.title.clone(),
+                description: channel.description.clone(),
+                username: channel.username.clone(),
+                posts: posts.to_vec(),
+                theme: channel.theme.clone(),
+            };
+            let pagination = Pagination {
+                current: index + 1,
+                page_count,
+                pages: pages.clone(),
+                previous: index.checked_sub(1).map(|i| pages[i].clone()),
+                next: pages.get(index + 1).cloned(),
+            };
+
+            let mut context = Context::new();
+            context.insert("channel", &page_channel);
+            context.insert("pagination", &pagination);
+            files.push((
+                pages[index].clone(),
+                theme.tera.render(ALBUM_TEMPLATE, &context)?,
+            ));
+        }
+
+        if page_count > 1 {
+            let post_pages: BTreeMap<i32, &String> = page_posts
+                .iter()
+                .zip(&pages)
+                .flat_map(|(posts, page)| posts.iter().map(move |post| (post.id, page)))
+                .collect();
+            files.push((
+                "posts.js".to_string(),
+                format!("var postPages = {};\n", serde_json::to_string(&post_pages)?),
+            ));
+        }
+
+        Ok(files)
+    }
+}
+
+/// Removes cached renders of albums which no longer exist
+fn prune_render_cache(cache_folder: &Path, telegram_data: &TelegramData) -> io::Result<()> {
+    for entry in fs::read_dir(cache_folder)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let album = file_name.strip_suffix(".hash").unwrap_or(&file_name);
+
+        if !telegram_data
+            .channels
+            .iter()
+            .any(|channel| channel.username == album)
+        {
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
 
     Ok(())
 }
@@ -457,24 +1420,38 @@ pub async fn generate_albums(
     user_id: u64,
     data_folder: &str,
     result_folder: &str,
-) -> Result<(u64, PathBuf), Box<dyn Error>> {
+    templates: &Templates,
+    archive_options: ArchiveOptions,
+    progress: &ProgressSender,
+) -> Result<GeneratedArchive, ArchiveError> {
     if username.is_empty() {
-        return Err("username is not specified".into());
+        return Err(ArchiveError::UsernameNotSpecified);
     }
 
     // Read the file contents
     let file_path = Path::new(data_folder)
         .join(user_id.to_string())
         .join("data.json");
-    let mut file = File::open(&file_path)?;
-    let mut json_data = String::new();
-    file.read_to_string(&mut json_data)?;
+    let telegram_data = read_telegram_data(&file_path).await?;
 
-    let telegram_data: TelegramData = serde_json::from_str(&json_data)?;
+    // Each job gets its own workspace, so that concurrent jobs never touch each other's files
+    let workspace = create_workspace(result_folder, user_id)?;
+    let albums_folder = workspace.path().join("albums");
 
     // Generate albums
     let mut counter: u64 = 0;
-    let tera = Tera::new("templates/**/*.html")?;
+
+    // Albums are rendered into the render cache and only re-rendered when their posts or the template change
+    let cache_folder = get_render_cache_folder(result_folder, user_id);
+    fs::create_dir_all(&cache_folder)?;
+    let renderer = AlbumRenderer {
+        templates,
+        user_theme: telegram_data.theme.as_deref(),
+        posts_per_page: archive_options.posts_per_page,
+        cache_folder: cache_folder.clone(),
+        albums_folder: &albums_folder,
+    };
+    let cache_lock = lock_render_cache(user_id).await;
 
     // Check if album exists
     let album_exists = telegram_data
@@ -482,13 +1459,21 @@ pub async fn generate_albums(
         .iter()
         .any(|channel| channel.username == username);
     if username != "<ALL>" && !album_exists {
-        return Err("Album not found!".into());
+        return Err(ArchiveError::AlbumNotFound);
     } else {
+        let album_count = if username == "<ALL>" {
+            telegram_data.channels.len()
+        } else {
+            1
+        };
+        let mut album = 0;
+
         for channel in telegram_data.channels.iter() {
             if username == "<ALL>" || username == channel.username {
-                match generate_single_album(&tera, channel, user_id, data_folder, result_folder)
-                    .await
-                {
+                album += 1;
+                progress.send_replace(JobProgress::Rendering { album, album_count });
+
+                match renderer.render(channel).await {
                     Ok(()) => {
                         info!(
                             "Successfully generated album \"{}\" for user #{}.",
@@ -512,29 +1497,214 @@ pub async fn generate_albums(
         }
     }
 
+    if let Err(e) = prune_render_cache(&cache_folder, &telegram_data) {
+        warn!(
+            "Unable to remove cached renders of deleted albums of user #{}: {}",
+            user_id, e
+        );
+    }
+    drop(cache_lock);
+
     if counter == 0 {
         // Return an error if counter is 0
-        return Err("no albums have been generated".into());
+        return Err(ArchiveError::NoAlbumsGenerated);
     }
 
-    let user_folder = Path::new(result_folder).join(user_id.to_string());
-    let result_file = Path::new(result_folder).join(format!(
-        "ArchiveGramBot-Archive-{}.zip",
+    progress.send_replace(JobProgress::Zipping {
+        album_count: counter as usize,
+    });
+
+    let archive_name = format!(
+        "ArchiveGramBot-Archive-{}",
         Utc::now().format("%Y-%m-%d_%H-%M-%S")
-    ));
+    );
+    let output_folder = workspace.path().to_path_buf();
+    let user_folder = Path::new(data_folder).join(user_id.to_string());
+
+    // Cancelling the job doesn't stop the blocking task, so it stops zipping on its own
+    // and keeps the workspace until it returns
+    let cancelled = progress.cancelled();
+    let (volumes, index_path, workspace) = run_blocking(move || {
+        let (volumes, index_path) = write_volumes(
+            &albums_folder,
+            &user_folder,
+            &output_folder,
+            &archive_name,
+            archive_options,
+            &cancelled,
+        )?;
+        Ok((volumes, index_path, workspace))
+    })
+    .await?;
+
+    Ok(GeneratedArchive {
+        album_count: counter,
+        volumes,
+        index_path,
+        workspace,
+    })
+}
+
+/// Lists files of a folder in a stable order, named by their path inside `archive_folder` of the archive
+fn list_archive_files(folder: &Path, archive_folder: &Path) -> io::Result<Vec<ArchiveFile>> {
+    let mut files = Vec::new();
+    if !folder.is_dir() {
+        return Ok(files);
+    }
+
+    for entry in walkdir::WalkDir::new(folder).sort_by_file_name() {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_file() {
+            let name = archive_folder
+                .join(
+                    entry
+                        .path()
+                        .strip_prefix(folder)
+                        .map_err(io::Error::other)?,
+                )
+                .to_string_lossy()
+                .to_string();
+            files.push(ArchiveFile {
+                path: entry.path().to_path_buf(),
+                name,
+                size: entry.metadata().map_err(io::Error::from)?.len(),
+            });
+        }
+    }
+
+    Ok(files)
+}
+
+/// Distributes rendered albums across volumes of at most `max_volume_size` bytes.
+/// Media files of an album are read straight from the user folder and spread across volumes,
+/// while its page, styles and images are repeated in every volume with its media,
+/// so that each volume can be opened on its own
+fn split_into_volumes(
+    albums_folder: &Path,
+    user_folder: &Path,
+    max_volume_size: u64,
+) -> io::Result<Vec<Volume>> {
+    let max_volume_size = max_volume_size.saturating_sub(VOLUME_SIZE_MARGIN);
+
+    let mut album_folders = Vec::new();
+    for entry in fs::read_dir(albums_folder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            album_folders.push(entry.path());
+        }
+    }
+    album_folders.sort();
+
+    let mut volumes = Vec::new();
+    let mut volume = Volume::default();
 
-    // Safely use unwrap() here as amount of albums is > 0
-    let album_zip = zip_folder(&user_folder, &result_file).unwrap();
+    for album_folder in album_folders {
+        let album = album_folder
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let pages = list_archive_files(&album_folder, Path::new(&album))?;
+        let media = list_archive_files(
+            &user_folder.join(&album),
+            &Path::new(&album).join("gallery"),
+        )?;
+        let pages_size: u64 = pages.iter().map(ArchiveFile::zipped_size).sum();
+
+        // An album without media still needs its pages in one of the volumes
+        let mut media_files: Vec<Option<&ArchiveFile>> = media.iter().map(Some).collect();
+        if media_files.is_empty() {
+            media_files.push(None);
+        }
+
+        for file in media_files {
+            let mut size = file.map_or(0, ArchiveFile::zipped_size);
+            if !volume.contains_album(&album) {
+                size += pages_size;
+            }
+
+            // A file which doesn't fit even into an empty volume gets one of its own
+            if volume.size + size > max_volume_size && !volume.files.is_empty() {
+                volumes.push(std::mem::take(&mut volume));
+            }
 
-    Ok((counter, album_zip))
+            if !volume.contains_album(&album) {
+                pages.iter().for_each(|page| volume.add(page));
+                volume.albums.push((album.clone(), 0));
+            }
+            if let Some(file) = file {
+                volume.add(file);
+                if let Some((_, media_count)) = volume.albums.last_mut() {
+                    *media_count += 1;
+                }
+            }
+        }
+    }
+
+    if !volume.files.is_empty() {
+        volumes.push(volume);
+    }
+
+    Ok(volumes)
+}
+
+/// Zips rendered albums into volumes and, if there is more than one, writes an index file describing them
+fn write_volumes(
+    albums_folder: &Path,
+    user_folder: &Path,
+    output_folder: &Path,
+    archive_name: &str,
+    options: ArchiveOptions,
+    cancelled: &AtomicBool,
+) -> Result<(Vec<PathBuf>, Option<PathBuf>), ArchiveError> {
+    let volumes = split_into_volumes(albums_folder, user_folder, options.max_volume_size)?;
+
+    // An archive which fits into a single volume keeps its usual name
+    if volumes.len() <= 1 {
+        let zip_path = output_folder.join(format!("{}.zip", archive_name));
+        let files = volumes.into_iter().next().unwrap_or_default().files;
+        zip_files(&files, &zip_path, options.compression, cancelled)?;
+        return Ok((vec![zip_path], None));
+    }
+
+    let mut volume_paths = Vec::new();
+    let mut index = format!(
+        "The archive is split into {} parts, each of them can be opened on its own.\n\
+         Album pages show only the media stored in the same part.\n",
+        volumes.len()
+    );
+
+    for (i, volume) in volumes.iter().enumerate() {
+        let file_name = format!("{}-part{}.zip", archive_name, i + 1);
+        zip_files(
+            &volume.files,
+            &output_folder.join(&file_name),
+            options.compression,
+            cancelled,
+        )?;
+
+        index.push_str(&format!("\n{}:\n", file_name));
+        for (album, media_count) in &volume.albums {
+            index.push_str(&format!("  {}: {} media files\n", album, media_count));
+        }
+        volume_paths.push(output_folder.join(file_name));
+    }
+
+    let index_path = output_folder.join(format!("{}-index.txt", archive_name));
+    fs::write(&index_path, index)?;
+
+    Ok((volume_paths, Some(index_path)))
 }
 
 pub async fn add_new_post(
     bot: Bot,
     msg: Message,
     data_folder: &str,
-    max_user_folder_size: u32,
-) -> Result<(), Box<dyn Error>> {
+    limits: &StorageLimits,
+    usage: &StorageUsage,
+    duplicate_policy: DuplicatePolicy,
+    perceptual_hashing: bool,
+) -> Result<u64, ArchiveError> {
     let user_id = msg.chat.id.0 as u64;
     let album_id = msg.forward_from_chat().map_or(0, |chat| chat.id.0);
     let album_username = msg
@@ -555,6 +1725,7 @@ pub async fn add_new_post(
         text: msg.text().unwrap_or_default().to_string(),
         photos: vec![],
         videos: vec![],
+        media: vec![],
     };
 
     let mut new_channel = TelegramChannel {
@@ -571,20 +1742,45 @@ pub async fn add_new_post(
             .to_string(),
         username: album_username.clone(),
         posts: vec![],
+        theme: None,
     };
 
     // Read the file contents
     let user_folder = Path::new(data_folder).join(user_id.to_string());
     let file_path = user_folder.join("data.json");
-    let user_folder_size = get_folder_size(&user_folder);
+    let free_disk_space = fs2::available_space(data_folder)?;
+    let previous_data_size = fs::metadata(&file_path).map_or(0, |m| m.len());
+    let media_size;
 
     if file_path.exists() {
         // If file exists, assume that it has correct format
-        let mut file = File::open(&file_path)?;
-        let mut json_data = String::new();
-        file.read_to_string(&mut json_data)?;
+        let telegram_data = read_telegram_data(&file_path).await?;
+
+        // Media of older posts is hashed once and saved right away, even if the new post is rejected
+        let (mut telegram_data, records_added) = {
+            let user_folder = user_folder.clone();
+            run_blocking(move || {
+                let mut telegram_data = telegram_data;
+                let records_added = telegram_data.add_missing_media_records(&user_folder);
+                Ok((telegram_data, records_added))
+            })
+            .await?
+        };
+        if records_added {
+            write_telegram_data(&file_path, &telegram_data).await?;
+        }
 
-        let mut telegram_data: TelegramData = serde_json::from_str(&json_data)?;
+        let known_media = telegram_data.get_media_locations();
+        let options = NewMediaOptions {
+            user_folder: &user_folder,
+            album_path: &album_path,
+            limits,
+            usage,
+            free_disk_space,
+            known_media: &known_media,
+            duplicate_policy,
+            perceptual_hashing,
+        };
 
         // Album already exists
         if let Some(channel) = telegram_data
@@ -594,15 +1790,7 @@ pub async fn add_new_post(
         {
             // Check if a post already exists
             if !channel.posts.iter().any(|post| post.id == post_id) {
-                new_post
-                    .add_media(
-                        bot,
-                        msg,
-                        &album_path,
-                        user_folder_size,
-                        max_user_folder_size,
-                    )
-                    .await?;
+                media_size = new_post.add_media(bot, msg, &options).await?;
                 channel.posts.push(new_post);
                 info!(
                     "Post #{} in album \"{}\" for user #{} successfully added to JSON file.",
@@ -613,19 +1801,11 @@ pub async fn add_new_post(
                     "Post #{} already exists in album \"{}\" for user #{}.",
                     post_id, album_username, user_id
                 );
-                return Err("Post already exists!".into());
+                return Err(ArchiveError::PostAlreadyExists);
             }
         } else {
             // Album not found, add the new album to the list of albums
-            new_post
-                .add_media(
-                    bot,
-                    msg,
-                    &album_path,
-                    user_folder_size,
-                    max_user_folder_size,
-                )
-                .await?;
+            media_size = new_post.add_media(bot, msg, &options).await?;
             new_channel.posts.push(new_post);
             telegram_data.channels.push(new_channel);
             info!(
@@ -634,35 +1814,175 @@ pub async fn add_new_post(
             );
         }
 
-        let updated_telegram_data = serde_json::to_string_pretty(&telegram_data)?;
-        fs::write(&file_path, updated_telegram_data)?;
+        write_telegram_data(&file_path, &telegram_data).await?;
     } else {
         // Create a user dir if it doesn't exist
         fs::create_dir_all(&user_folder)?;
 
         // Create new JSON file for specified user
-        new_post
-            .add_media(
-                bot,
-                msg,
-                &album_path,
-                user_folder_size,
-                max_user_folder_size,
-            )
-            .await?;
+        let options = NewMediaOptions {
+            user_folder: &user_folder,
+            album_path: &album_path,
+            limits,
+            usage,
+            free_disk_space,
+            known_media: &[],
+            duplicate_policy,
+            perceptual_hashing,
+        };
+        media_size = new_post.add_media(bot, msg, &options).await?;
         new_channel.posts.push(new_post);
         let data = TelegramData {
             channels: vec![new_channel],
+            theme: None,
         };
 
         // Serialize the data to JSON
-        let telegram_data = serde_json::to_string_pretty(&data)?;
-        fs::write(&file_path, telegram_data)?;
+        write_telegram_data(&file_path, &data).await?;
         info!(
             "JSON file for user #{} created, post #{} and album \"{}\" successfully added.",
             user_id, post_id, album_username
         );
     }
 
-    Ok(())
+    // Report how much the user folder has grown, so that the usage index can be updated without walking it
+    let data_size = fs::metadata(&file_path).map_or(0, |m| m.len());
+    Ok((media_size + data_size).saturating_sub(previous_data_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempfile::TempDir;
+
+    const KB: u64 = 1024;
+    /// Volume size leaving 300 KB for files after the safety margin
+    const MAX_VOLUME_SIZE: u64 = VOLUME_SIZE_MARGIN + 300 * KB;
+
+    /// Creates a rendered album with its pages and styles, and its media files of the given sizes
+    fn create_album(workspace: &Path, album: &str, media_sizes: &[u64]) {
+        let album_folder = workspace.join("albums").join(album);
+        fs::create_dir_all(album_folder.join("css")).unwrap();
+        fs::write(album_folder.join("index.html"), "<html></html>").unwrap();
+        fs::write(album_folder.join("page-2.html"), "<html></html>").unwrap();
+        fs::write(album_folder.join("css/style.css"), "body {}").unwrap();
+
+        let media_folder = workspace.join("user").join(album);
+        fs::create_dir_all(&media_folder).unwrap();
+        for (i, size) in media_sizes.iter().enumerate() {
+            fs::write(
+                media_folder.join(format!("{:03}.jpg", i)),
+                vec![0u8; *size as usize],
+            )
+            .unwrap();
+        }
+    }
+
+    fn write_test_volumes(workspace: &Path) -> (Vec<PathBuf>, Option<PathBuf>) {
+        let output_folder = workspace.join("output");
+        fs::create_dir_all(&output_folder).unwrap();
+        write_volumes(
+            &workspace.join("albums"),
+            &workspace.join("user"),
+            &output_folder,
+            "archive",
+            ArchiveOptions {
+                compression: Compression::Stored,
+                max_volume_size: MAX_VOLUME_SIZE,
+                posts_per_page: 0,
+            },
+            &AtomicBool::new(false),
+        )
+        .unwrap()
+    }
+
+    fn get_file_names(volume: &Path) -> Vec<String> {
+        let zip = zip::ZipArchive::new(File::open(volume).unwrap()).unwrap();
+        zip.file_names().map(str::to_string).collect()
+    }
+
+    fn get_size(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().len()
+    }
+
+    /// Checks that every volume holds the pages of each album whose media it holds
+    fn assert_pages_in_volumes(volumes: &[PathBuf]) {
+        for volume in volumes {
+            let names = get_file_names(volume);
+            for name in &names {
+                let album = name.split('/').next().unwrap();
+                for page in ["index.html", "page-2.html", "css/style.css"] {
+                    let page = format!("{}/{}", album, page);
+                    assert!(names.contains(&page), "{} is missing in {:?}", page, volume);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_album_is_split_into_volumes() {
+        let workspace = TempDir::new().unwrap();
+        create_album(workspace.path(), "large", &[100 * KB; 8]);
+        create_album(workspace.path(), "small", &[10 * KB]);
+
+        let (volumes, index_path) = write_test_volumes(workspace.path());
+
+        assert!(volumes.len() > 2);
+        for volume in &volumes {
+            assert!(get_size(volume) <= MAX_VOLUME_SIZE - VOLUME_SIZE_MARGIN);
+        }
+        assert_pages_in_volumes(&volumes);
+
+        let media_count = volumes
+            .iter()
+            .flat_map(|volume| get_file_names(volume))
+            .filter(|name| name.contains("/gallery/"))
+            .count();
+        assert_eq!(media_count, 9);
+
+        let index = fs::read_to_string(index_path.unwrap()).unwrap();
+        assert!(index.contains(&format!("split into {} parts", volumes.len())));
+    }
+
+    #[test]
+    fn file_larger_than_volume_gets_own_volume() {
+        let workspace = TempDir::new().unwrap();
+        create_album(workspace.path(), "album", &[50 * KB, 500 * KB, 50 * KB]);
+
+        let (volumes, _) = write_test_volumes(workspace.path());
+
+        assert_eq!(volumes.len(), 3);
+        assert_pages_in_volumes(&volumes);
+        for volume in &volumes {
+            let names = get_file_names(volume);
+            if names.contains(&"album/gallery/001.jpg".to_string()) {
+                // Only the pages of the album are added to the large file
+                assert_eq!(names.len(), 4);
+            } else {
+                assert!(get_size(volume) <= MAX_VOLUME_SIZE - VOLUME_SIZE_MARGIN);
+            }
+        }
+    }
+
+    #[test]
+    fn album_without_media_is_archived_with_its_pages() {
+        let workspace = TempDir::new().unwrap();
+        create_album(workspace.path(), "empty", &[]);
+
+        let (volumes, index_path) = write_test_volumes(workspace.path());
+
+        assert_eq!(volumes.len(), 1);
+        assert!(index_path.is_none());
+        let mut names = get_file_names(&volumes[0]);
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "empty/css/style.css",
+                "empty/index.html",
+                "empty/page-2.html"
+            ]
+        );
+    }
 }
@@ -0,0 +1,378 @@
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tera::Tera;
+
+/// Template every album page is rendered from
+pub const ALBUM_TEMPLATE: &str = "content.html";
+
+/// Theme used for albums of users who haven't chosen one
+pub const DEFAULT_THEME: &str = "cards";
+
+/// Folder inside `template_folder` holding a subfolder per theme
+const THEMES_FOLDER: &str = "themes";
+
+/// Folders whose files are copied next to every album page
+const ASSET_FOLDERS: [&str; 2] = ["css", "img"];
+
+type EmbeddedFile = (&'static str, &'static [u8]);
+
+const BOOTSTRAP_FILES: [EmbeddedFile; 2] = [
+    (
+        "css/bootstrap.min.css",
+        include_bytes!("../templates/css/bootstrap.min.css"),
+    ),
+    (
+        "css/bootstrap.min.css.map",
+        include_bytes!("../templates/css/bootstrap.min.css.map"),
+    ),
+];
+
+const FAVICON_FILES: [EmbeddedFile; 3] = [
+    (
+        "img/favicon-16x16.png",
+        include_bytes!("../templates/img/favicon-16x16.png"),
+    ),
+    (
+        "img/favicon-32x32.png",
+        include_bytes!("../templates/img/favicon-32x32.png"),
+    ),
+    (
+        "img/favicon.ico",
+        include_bytes!("../templates/img/favicon.ico"),
+    ),
+];
+
+/// Partials included by the pages of every theme, which a theme can replace with its own file of the same name
+const PARTIAL_FILES: [EmbeddedFile; 2] = [
+    (
+        "pagination.html",
+        include_bytes!("../templates/partials/pagination.html"),
+    ),
+    (
+        "posts-redirect.html",
+        include_bytes!("../templates/partials/posts-redirect.html"),
+    ),
+];
+
+/// Themes compiled into the binary, so that the bot works from any working directory.
+/// Each entry holds the theme's own files and whether it uses Bootstrap
+const DEFAULT_THEMES: [(&str, &[EmbeddedFile], bool); 3] = [
+    (
+        "cards",
+        &[
+            (
+                "base.html",
+                include_bytes!("../templates/themes/cards/base.html"),
+            ),
+            (
+                "content.html",
+                include_bytes!("../templates/themes/cards/content.html"),
+            ),
+        ],
+        true,
+    ),
+    (
+        "grid",
+        &[
+            (
+                "base.html",
+                include_bytes!("../templates/themes/grid/base.html"),
+            ),
+            (
+                "content.html",
+                include_bytes!("../templates/themes/grid/content.html"),
+            ),
+            (
+                "css/grid.css",
+                include_bytes!("../templates/themes/grid/css/grid.css"),
+            ),
+        ],
+        true,
+    ),
+    (
+        "print",
+        &[
+            (
+                "base.html",
+                include_bytes!("../templates/themes/print/base.html"),
+            ),
+            (
+                "content.html",
+                include_bytes!("../templates/themes/print/content.html"),
+            ),
+            (
+                "pagination.html",
+                include_bytes!("../templates/themes/print/pagination.html"),
+            ),
+            (
+                "css/print.css",
+                include_bytes!("../templates/themes/print/css/print.css"),
+            ),
+        ],
+        false,
+    ),
+];
+
+type ThemeFiles = BTreeMap<String, Cow<'static, [u8]>>;
+
+/// Templates and assets of a single theme
+pub struct Theme {
+    pub tera: Tera,
+    /// Files of the `css` and `img` folders by their path relative to the album page
+    pub assets: ThemeFiles,
+    /// Hash of all files of the theme at the time they were loaded, part of the render cache key
+    pub hash: String,
+}
+
+/// Album themes parsed once and shared by all generation jobs
+pub struct Templates {
+    pub themes: BTreeMap<String, Theme>,
+}
+
+impl Templates {
+    /// Returns the theme with the given name, or the default theme if there is no such theme,
+    /// e.g. because it has been removed from the template folder since it was chosen
+    pub fn get_theme(&self, name: Option<&str>) -> &Theme {
+        name.and_then(|name| self.themes.get(name))
+            .or_else(|| self.themes.get(DEFAULT_THEME))
+            .expect("default theme is always loaded")
+    }
+}
+
+/// Handle to the current templates, which can be replaced with `/reloadtemplates` while the bot is running
+#[derive(Clone)]
+pub struct TemplateStore(Arc<RwLock<Arc<Templates>>>);
+
+impl TemplateStore {
+    pub fn new(templates: Templates) -> Self {
+        TemplateStore(Arc::new(RwLock::new(Arc::new(templates))))
+    }
+
+    pub fn get(&self) -> Arc<Templates> {
+        // A poisoned lock still holds valid templates as they are only ever replaced as a whole
+        match self.0.read() {
+            Ok(templates) => templates.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn set(&self, templates: Templates) {
+        match self.0.write() {
+            Ok(mut current) => *current = Arc::new(templates),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(templates),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Read(io::Error),
+    Parse {
+        theme: String,
+        error: tera::Error,
+    },
+    Missing {
+        theme: String,
+        template: &'static str,
+    },
+    InvalidThemeName(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Read(e) => write!(f, "could not read templates: {}", e),
+            TemplateError::Parse { theme, error } => {
+                // Tera keeps the line and column of a syntax error in the source of its error
+                write!(
+                    f,
+                    "could not parse templates of theme \"{}\": {}",
+                    theme, error
+                )?;
+                let mut source = error.source();
+                while let Some(e) = source {
+                    write!(f, ": {}", e)?;
+                    source = e.source();
+                }
+                Ok(())
+            }
+            TemplateError::Missing { theme, template } => write!(
+                f,
+                "template \"{}\" of theme \"{}\" is missing",
+                template, theme
+            ),
+            TemplateError::InvalidThemeName(name) => write!(
+                f,
+                "theme name \"{}\" may only contain letters, digits, \"-\" and \"_\"",
+                name
+            ),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+/// Loads the built-in themes and the themes of `template_folder`. Each folder inside its `themes` folder is a theme:
+/// its files replace the files of the built-in theme with the same name, or make up a new theme
+/// which starts out with Bootstrap and the favicons. Other files and folders of `template_folder`,
+/// such as the shared `css` and `img` folders of this repository, are ignored
+pub fn load_templates(template_folder: Option<&str>) -> Result<Templates, TemplateError> {
+    let mut theme_files: BTreeMap<String, ThemeFiles> = DEFAULT_THEMES
+        .iter()
+        .map(|(name, files, uses_bootstrap)| {
+            (name.to_string(), get_default_files(files, *uses_bootstrap))
+        })
+        .collect();
+
+    if let Some(template_folder) = template_folder {
+        // A template folder without themes overrides none of the built-in themes
+        let entries = match fs::read_dir(Path::new(template_folder).join(THEMES_FOLDER)) {
+            Ok(entries) => entries.collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(TemplateError::Read(e)),
+        };
+        for entry in entries {
+            let entry = entry.map_err(TemplateError::Read)?;
+            if !entry.file_type().map_err(TemplateError::Read)?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !is_valid_theme_name(&name) {
+                return Err(TemplateError::InvalidThemeName(name));
+            }
+
+            let files = theme_files
+                .entry(name)
+                .or_insert_with(|| get_default_files(&[], true));
+            read_theme_folder(&entry.path(), files).map_err(TemplateError::Read)?;
+        }
+    }
+
+    let mut themes = BTreeMap::new();
+    for (name, files) in theme_files {
+        let theme = load_theme(&name, files)?;
+        themes.insert(name, theme);
+    }
+
+    Ok(Templates { themes })
+}
+
+pub fn is_valid_theme_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+fn get_default_files(files: &[EmbeddedFile], uses_bootstrap: bool) -> ThemeFiles {
+    let shared_files: &[EmbeddedFile] = if uses_bootstrap {
+        &BOOTSTRAP_FILES
+    } else {
+        &[]
+    };
+
+    shared_files
+        .iter()
+        .chain(FAVICON_FILES.iter())
+        .chain(PARTIAL_FILES.iter())
+        .chain(files.iter())
+        .map(|(name, contents)| (name.to_string(), Cow::Borrowed(*contents)))
+        .collect()
+}
+
+/// Parses the templates of a theme and checks that the album template is present
+fn load_theme(name: &str, files: ThemeFiles) -> Result<Theme, TemplateError> {
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    for (file_name, contents) in &files {
+        hasher.update(file_name.as_bytes());
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(contents);
+    }
+    let hash = format!("{:x}", hasher.finalize());
+
+    let mut html_files = Vec::new();
+    let mut assets = BTreeMap::new();
+    for (file_name, contents) in files {
+        if file_name.ends_with(".html") {
+            let contents = String::from_utf8(contents.into_owned()).map_err(|e| {
+                TemplateError::Read(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("\"{}/{}\" is not valid UTF-8: {}", name, file_name, e),
+                ))
+            })?;
+            html_files.push((file_name, contents));
+        } else if ASSET_FOLDERS
+            .iter()
+            .any(|folder| file_name.starts_with(&format!("{}/", folder)))
+        {
+            assets.insert(file_name, contents);
+        }
+    }
+
+    // All templates are added at once, so that they can extend each other regardless of order
+    let mut tera = Tera::default();
+    tera.add_raw_templates(html_files)
+        .map_err(|error| TemplateError::Parse {
+            theme: name.to_string(),
+            error,
+        })?;
+
+    if !tera.get_template_names().any(|name| name == ALBUM_TEMPLATE) {
+        return Err(TemplateError::Missing {
+            theme: name.to_string(),
+            template: ALBUM_TEMPLATE,
+        });
+    }
+
+    Ok(Theme { tera, assets, hash })
+}
+
+/// Reads all files of the theme folder into `files` by their path relative to it
+fn read_theme_folder(theme_folder: &Path, files: &mut ThemeFiles) -> io::Result<()> {
+    for entry in walkdir::WalkDir::new(theme_folder) {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Ok(relative_path) = entry.path().strip_prefix(theme_folder) else {
+            continue;
+        };
+        // Names use `/` on every platform, like the built-in ones
+        let name = relative_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.insert(name, Cow::Owned(fs::read(entry.path())?));
+    }
+
+    Ok(())
+}
+
+/// Writes the assets into the album folder, replacing whatever the `css` and `img` folders held before
+pub fn write_assets(assets: &ThemeFiles, album_folder: &Path) -> io::Result<()> {
+    for folder in ASSET_FOLDERS {
+        let folder = album_folder.join(folder);
+        if folder.exists() {
+            fs::remove_dir_all(&folder)?;
+        }
+    }
+
+    for (name, contents) in assets {
+        let path = album_folder.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+    }
+
+    Ok(())
+}
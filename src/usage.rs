@@ -0,0 +1,143 @@
+use log2::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::operations::{get_user_folder_ids, ArchiveError};
+use crate::utils::get_folder_size;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct UsageIndex {
+    // Size of each user folder in bytes
+    #[serde(default)]
+    users: HashMap<u64, u64>,
+}
+
+/// Cached sizes of user folders, so that incoming posts don't require walking the whole folder
+#[derive(Clone)]
+pub struct UsageStore {
+    file_path: PathBuf,
+    data_folder: PathBuf,
+    usage_index: Arc<Mutex<UsageIndex>>,
+}
+
+impl UsageStore {
+    pub fn load(data_folder: &str) -> Result<Self, ArchiveError> {
+        let file_path = Path::new(data_folder).join("usage.json");
+
+        let usage_index = if file_path.exists() {
+            let json_data = fs::read_to_string(&file_path)?;
+            serde_json::from_str(&json_data)?
+        } else {
+            UsageIndex::default()
+        };
+
+        let usage_store = UsageStore {
+            file_path,
+            data_folder: PathBuf::from(data_folder),
+            usage_index: Arc::new(Mutex::new(usage_index)),
+        };
+
+        // Without an index yet, build it from disk so that the global limit is enforced right away
+        if !usage_store.file_path.exists() {
+            let mut usage_index = usage_store.lock();
+            usage_index.users = measure_user_folders(&usage_store.data_folder)?;
+            usage_store.save(&usage_index)?;
+        }
+
+        Ok(usage_store)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, UsageIndex> {
+        // The index is only modified together with saving it, so a poisoned lock still holds valid data
+        match self.usage_index.lock() {
+            Ok(usage_index) => usage_index,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn save(&self, usage_index: &UsageIndex) -> Result<(), ArchiveError> {
+        let json_data = serde_json::to_string(usage_index)?;
+        fs::write(&self.file_path, json_data)?;
+        Ok(())
+    }
+
+    async fn measure(&self, user_id: u64) -> Result<u64, ArchiveError> {
+        // Walking a large folder takes a while, keep it off the async runtime
+        let user_folder = self.data_folder.join(user_id.to_string());
+        tokio::task::spawn_blocking(move || get_folder_size(&user_folder))
+            .await
+            .map_err(|e| ArchiveError::Io(std::io::Error::other(e)))
+    }
+
+    pub async fn get_user_folder_size(&self, user_id: u64) -> u64 {
+        if let Some(user_folder_size) = self.lock().users.get(&user_id) {
+            return *user_folder_size;
+        }
+
+        // Users missing from the index have either no folder yet or appeared since the last reconciliation
+        match self.measure(user_id).await {
+            Ok(user_folder_size) => {
+                self.lock().users.insert(user_id, user_folder_size);
+                user_folder_size
+            }
+            Err(e) => {
+                error!("Unable to measure folder of user #{}: {}", user_id, e);
+                0
+            }
+        }
+    }
+
+    pub fn get_data_folder_size(&self) -> u64 {
+        self.lock().users.values().sum()
+    }
+
+    pub async fn add(&self, user_id: u64, bytes: u64) -> Result<(), ArchiveError> {
+        let user_folder_size = self.get_user_folder_size(user_id).await;
+
+        let mut usage_index = self.lock();
+        usage_index
+            .users
+            .insert(user_id, user_folder_size.saturating_add(bytes));
+        self.save(&usage_index)
+    }
+
+    /// Measures the user folder again after files have been deleted or rewritten
+    pub async fn refresh(&self, user_id: u64) -> Result<(), ArchiveError> {
+        let user_folder_size = self.measure(user_id).await?;
+
+        let mut usage_index = self.lock();
+        if user_folder_size == 0 {
+            usage_index.users.remove(&user_id);
+        } else {
+            usage_index.users.insert(user_id, user_folder_size);
+        }
+        self.save(&usage_index)
+    }
+
+    /// Rebuilds the whole index from disk to correct any drift of incremental updates
+    pub async fn reconcile(&self) -> Result<(), ArchiveError> {
+        let data_folder = self.data_folder.clone();
+        let users = tokio::task::spawn_blocking(move || measure_user_folders(&data_folder))
+            .await
+            .map_err(|e| ArchiveError::Io(std::io::Error::other(e)))??;
+
+        let mut usage_index = self.lock();
+        usage_index.users = users;
+        self.save(&usage_index)
+    }
+}
+
+fn measure_user_folders(data_folder: &Path) -> Result<HashMap<u64, u64>, ArchiveError> {
+    Ok(get_user_folder_ids(&data_folder.to_string_lossy())?
+        .into_iter()
+        .map(|user_id| {
+            (
+                user_id,
+                get_folder_size(&data_folder.join(user_id.to_string())),
+            )
+        })
+        .collect())
+}
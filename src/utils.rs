@@ -1,123 +1,702 @@
-use serde::Deserialize;
-use std::path::{Path, PathBuf};
-use std::{fs, fs::File};
-use std::{io, io::prelude::*};
-use zip::write::FileOptions;
-use zip::CompressionMethod;
-
-#[derive(Debug, Deserialize, Clone)]
-pub struct Config {
-    pub teloxide_token: String,
-    pub data_folder: String,
-    pub max_user_folder_size: u32,
-    pub result_folder: String,
-    pub log_path: String,
-    pub restrict_access: bool,
-    pub allowed_users: Vec<u64>,
-}
-
-pub fn load_config(file: &str) -> Config {
-    let contents = match fs::read_to_string(file) {
-        Ok(_a) => _a,
-        Err(_) => panic!("Could not read file \"{}\"", file),
-    };
-
-    let config: Config = match toml::from_str(&contents) {
-        Ok(_b) => _b,
-        Err(_) => panic!("Unable to load data from \"{}\"", file),
-    };
-
-    config
-}
-
-pub fn get_folder_size(folder_path: &Path) -> u32 {
-    let mut total_size: u32 = 0;
-
-    for entry in walkdir::WalkDir::new(folder_path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if entry.file_type().is_file() {
-            total_size += entry.metadata().map_or(0, |m| m.len()) as u32;
-        }
-    }
-
-    total_size
-}
-
-pub fn convert_to_mb(bytes: u32) -> f64 {
-    (bytes as f64 / (1024.0 * 1024.0) * 100.0).round() / 100.0
-}
-
-pub fn copy_dir_all(src: &PathBuf, dst: &PathBuf) -> io::Result<()> {
-    if src.exists() && src.is_dir() {
-        fs::create_dir_all(dst)?;
-        for entry in fs::read_dir(src)? {
-            let entry = entry?;
-            let ty = entry.file_type()?;
-            if ty.is_dir() {
-                copy_dir_all(&entry.path(), &dst.join(entry.file_name()))?;
-            } else {
-                fs::copy(entry.path(), dst.join(entry.file_name()))?;
-            }
-        }
-    }
-
-    Ok(())
-}
-
-pub fn truncate_string(s: &str, max_length: usize) -> String {
-    if s.chars().count() > max_length {
-        s.chars().take(max_length).collect::<String>() + "..."
-    } else {
-        s.to_string()
-    }
-}
-
-pub fn zip_folder(
-    folder_path: &PathBuf,
-    result_file: &PathBuf,
-) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    // Create a zip file
-    let file = File::create(result_file)?;
-    let mut zip = zip::ZipWriter::new(file);
-
-    // Walk through the files in the folder
-    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
-
-    for entry in walkdir::WalkDir::new(folder_path) {
-        let entry = entry?;
-        let relative_path = entry.path().strip_prefix(folder_path)?;
-
-        if entry.file_type().is_file() {
-            // Add each file to the zip archive
-            zip.start_file(relative_path.to_str().unwrap(), options)?;
-            let mut file = File::open(entry.path())?;
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer)?;
-            zip.write_all(&buffer)?;
-        }
-    }
-
-    Ok(result_file.clone())
-}
-
-pub async fn delete_contents_of_folder(folder_path: &str) -> io::Result<()> {
-    // Convert the folder path to a Path
-    let path = Path::new(folder_path);
-
-    // Iterate over the contents of the folder
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let entry_path = entry.path();
-
-        // Remove files or directories
-        if entry_path.is_file() {
-            fs::remove_file(entry_path)?;
-        } else if entry_path.is_dir() {
-            fs::remove_dir_all(entry_path)?;
-        }
-    }
-
-    Ok(())
-}
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::{fs, fs::File};
+use std::{io, io::prelude::*};
+use tempfile::TempDir;
+use zip::result::ZipResult;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    pub teloxide_token: String,
+    pub data_folder: String,
+    pub max_user_folder_size: u32,
+    #[serde(default)]
+    pub user_quotas: HashMap<String, u32>,
+    #[serde(default)]
+    pub max_data_folder_size: Option<u32>,
+    #[serde(default)]
+    pub min_free_disk_space: u32,
+    pub result_folder: String,
+    pub log_path: String,
+    pub restrict_access: bool,
+    pub allowed_users: Vec<u64>,
+    #[serde(default)]
+    pub admins: Vec<u64>,
+    #[serde(default)]
+    pub read_only_users: Vec<u64>,
+    #[serde(default)]
+    pub create_folders: bool,
+    #[serde(default)]
+    pub template_folder: Option<String>,
+    #[serde(default = "default_posts_per_page")]
+    pub posts_per_page: usize,
+    #[serde(default)]
+    pub perceptual_hashing: bool,
+    #[serde(default = "default_similarity_threshold")]
+    pub similarity_threshold: u32,
+    #[serde(default = "default_usage_reconciliation_interval")]
+    pub usage_reconciliation_interval: u64,
+    #[serde(default = "default_quota_warning_thresholds")]
+    pub quota_warning_thresholds: Vec<u8>,
+    #[serde(default = "default_max_concurrent_jobs")]
+    pub max_concurrent_jobs: usize,
+    #[serde(default = "default_archive_compression")]
+    pub archive_compression: Compression,
+    #[serde(default)]
+    pub download_server_address: Option<SocketAddr>,
+    #[serde(default)]
+    pub download_base_url: Option<String>,
+    #[serde(default)]
+    pub download_secret: String,
+    #[serde(default = "default_download_link_lifetime")]
+    pub download_link_lifetime: u64,
+    #[serde(default)]
+    pub bot_api_url: Option<url::Url>,
+    #[serde(default)]
+    pub bot_api_local_mode: bool,
+}
+
+/// File size limits of the Bot API server the bot is connected to
+#[derive(Debug, Clone, Copy)]
+pub struct BotApiLimits {
+    pub max_photo_size_in_mb: u32,
+    pub max_video_size_in_mb: u32,
+    pub max_upload_size_in_mb: u32,
+}
+
+/// Returns file size limits for the configured Bot API mode, see
+/// https://core.telegram.org/bots/api#sending-files and
+/// https://core.telegram.org/bots/api#using-a-local-bot-api-server
+pub fn get_bot_api_limits(config: &Config) -> BotApiLimits {
+    if config.bot_api_local_mode {
+        BotApiLimits {
+            max_photo_size_in_mb: 2000,
+            max_video_size_in_mb: 2000,
+            max_upload_size_in_mb: 2000,
+        }
+    } else {
+        BotApiLimits {
+            max_photo_size_in_mb: 5,
+            max_video_size_in_mb: 20,
+            max_upload_size_in_mb: 20,
+        }
+    }
+}
+
+/// Compression method for text and HTML files of generated archives.
+/// Photos and videos are already compressed and always stored as-is
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Stored,
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    fn method(self) -> CompressionMethod {
+        match self {
+            Compression::Stored => CompressionMethod::Stored,
+            Compression::Deflate => CompressionMethod::Deflated,
+            Compression::Zstd => CompressionMethod::Zstd,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Stored => write!(f, "stored"),
+            Compression::Deflate => write!(f, "deflate"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "stored" => Ok(Compression::Stored),
+            "deflate" => Ok(Compression::Deflate),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Handle to the current config, which can be replaced while the bot is running
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        // A poisoned lock still holds a valid config as it is only ever replaced as a whole
+        match self.0.read() {
+            Ok(config) => config.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn set(&self, config: Config) {
+        match self.0.write() {
+            Ok(mut current) => *current = Arc::new(config),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(config),
+        }
+    }
+}
+
+fn default_posts_per_page() -> usize {
+    100
+}
+
+fn default_similarity_threshold() -> u32 {
+    6
+}
+
+fn default_usage_reconciliation_interval() -> u64 {
+    60
+}
+
+fn default_quota_warning_thresholds() -> Vec<u8> {
+    vec![80, 95]
+}
+
+fn default_max_concurrent_jobs() -> usize {
+    2
+}
+
+fn default_archive_compression() -> Compression {
+    Compression::Deflate
+}
+
+fn default_download_link_lifetime() -> u64 {
+    24
+}
+
+/// Prefix of environment variables overriding config fields, e.g. `ARCHIVEGRAMBOT_TELOXIDE_TOKEN`
+const ENV_PREFIX: &str = "ARCHIVEGRAMBOT_";
+
+/// Environment variables with the same prefix which set command-line arguments instead
+const ENV_ARGUMENTS: [&str; 2] = ["ARCHIVEGRAMBOT_CONFIG", "ARCHIVEGRAMBOT_LOG_LEVEL"];
+
+/// Config fields holding text, so their values are never parsed as TOML.
+/// A token or secret consisting only of digits would otherwise become a number
+const STRING_FIELDS: [&str; 10] = [
+    "teloxide_token",
+    "data_folder",
+    "result_folder",
+    "log_path",
+    "template_folder",
+    "archive_compression",
+    "download_server_address",
+    "download_base_url",
+    "download_secret",
+    "bot_api_url",
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        file: String,
+        source: io::Error,
+    },
+    Parse {
+        file: String,
+        source: toml::de::Error,
+    },
+    Invalid {
+        field: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { file, source } => {
+                write!(f, "could not read file \"{}\": {}", file, source)
+            }
+            // TOML errors already name the offending key together with its line and column
+            ConfigError::Parse { file, source } => {
+                write!(f, "could not parse file \"{}\": {}", file, source)
+            }
+            ConfigError::Invalid { field, reason } => {
+                write!(f, "invalid value of \"{}\": {}", field, reason)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            ConfigError::Parse { source, .. } => Some(source),
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+pub fn load_config(file: &str, template_folder: Option<&str>) -> Result<Config, ConfigError> {
+    let overrides = get_env_overrides();
+
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        // The whole config may be passed through environment variables, e.g. in a container
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !overrides.is_empty() => String::new(),
+        Err(source) => {
+            return Err(ConfigError::Read {
+                file: file.to_string(),
+                source,
+            })
+        }
+    };
+
+    let parse_error = |source| ConfigError::Parse {
+        file: file.to_string(),
+        source,
+    };
+
+    let mut config: Config = if overrides.is_empty() {
+        // Deserialize the file directly to keep line numbers in error messages
+        toml::from_str(&contents).map_err(parse_error)?
+    } else {
+        let mut table: toml::value::Table = toml::from_str(&contents).map_err(parse_error)?;
+        table.extend(overrides);
+        Config::deserialize(toml::Value::Table(table)).map_err(parse_error)?
+    };
+
+    if let Some(template_folder) = template_folder {
+        config.template_folder = Some(template_folder.to_string());
+    }
+
+    validate_config(&config)?;
+
+    Ok(config)
+}
+
+fn get_env_overrides() -> toml::value::Table {
+    let mut overrides = toml::value::Table::new();
+
+    for (name, value) in std::env::vars() {
+        if ENV_ARGUMENTS.contains(&name.as_str()) {
+            continue;
+        }
+
+        if let Some(field) = name.strip_prefix(ENV_PREFIX) {
+            let field = field.to_lowercase();
+            if STRING_FIELDS.contains(&field.as_str()) {
+                overrides.insert(field, toml::Value::String(value));
+                continue;
+            }
+
+            // Numbers, booleans and arrays are written as TOML values,
+            // anything that is not a valid TOML value is taken as plain text
+            let value = toml::from_str::<toml::value::Table>(&format!("value = {}", value))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or(toml::Value::String(value));
+            overrides.insert(field, value);
+        }
+    }
+
+    overrides
+}
+
+fn validate_config(config: &Config) -> Result<(), ConfigError> {
+    if config.teloxide_token.trim().is_empty() || config.teloxide_token.starts_with('<') {
+        return Err(ConfigError::Invalid {
+            field: "teloxide_token",
+            reason: "a bot token received from @BotFather is required".to_string(),
+        });
+    }
+
+    if config.max_user_folder_size == 0 {
+        return Err(ConfigError::Invalid {
+            field: "max_user_folder_size",
+            reason: "size limit must be greater than 0".to_string(),
+        });
+    }
+
+    if let Some((user, _)) = config
+        .user_quotas
+        .iter()
+        .find(|(user, size)| user.parse::<u64>().is_err() || **size == 0)
+    {
+        return Err(ConfigError::Invalid {
+            field: "user_quotas",
+            reason: format!(
+                "\"{}\" must be a user ID with a size limit greater than 0",
+                user
+            ),
+        });
+    }
+
+    if config.max_data_folder_size == Some(0) {
+        return Err(ConfigError::Invalid {
+            field: "max_data_folder_size",
+            reason: "size limit must be greater than 0".to_string(),
+        });
+    }
+
+    if config.max_concurrent_jobs == 0 {
+        return Err(ConfigError::Invalid {
+            field: "max_concurrent_jobs",
+            reason: "at least one job must be allowed to run".to_string(),
+        });
+    }
+
+    if config.usage_reconciliation_interval == 0 {
+        return Err(ConfigError::Invalid {
+            field: "usage_reconciliation_interval",
+            reason: "interval must be greater than 0".to_string(),
+        });
+    }
+
+    if let Some(threshold) = config
+        .quota_warning_thresholds
+        .iter()
+        .find(|threshold| !(1..=100).contains(*threshold))
+    {
+        return Err(ConfigError::Invalid {
+            field: "quota_warning_thresholds",
+            reason: format!("{} is not a percentage between 1 and 100", threshold),
+        });
+    }
+
+    if config.download_server_address.is_some() {
+        if config.download_secret.len() < 16 || config.download_secret.starts_with('<') {
+            return Err(ConfigError::Invalid {
+                field: "download_secret",
+                reason: "a secret of at least 16 characters is required to sign download links"
+                    .to_string(),
+            });
+        }
+
+        if config
+            .download_base_url
+            .as_ref()
+            .is_some_and(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            return Err(ConfigError::Invalid {
+                field: "download_base_url",
+                reason: "URL must start with \"http://\" or \"https://\"".to_string(),
+            });
+        }
+
+        if config.download_link_lifetime == 0 {
+            return Err(ConfigError::Invalid {
+                field: "download_link_lifetime",
+                reason: "lifetime must be greater than 0".to_string(),
+            });
+        }
+    }
+
+    if config.bot_api_local_mode && config.bot_api_url.is_none() {
+        return Err(ConfigError::Invalid {
+            field: "bot_api_local_mode",
+            reason: "local mode requires \"bot_api_url\" of a self-hosted Bot API server"
+                .to_string(),
+        });
+    }
+
+    if config.restrict_access
+        && config.allowed_users.is_empty()
+        && config.admins.is_empty()
+        && config.read_only_users.is_empty()
+    {
+        return Err(ConfigError::Invalid {
+            field: "allowed_users",
+            reason: "at least one user is required when \"restrict_access\" is enabled".to_string(),
+        });
+    }
+
+    validate_folder("data_folder", &config.data_folder, config.create_folders)?;
+    validate_folder(
+        "result_folder",
+        &config.result_folder,
+        config.create_folders,
+    )?;
+
+    if let Some(template_folder) = &config.template_folder {
+        if !Path::new(template_folder).is_dir() {
+            return Err(ConfigError::Invalid {
+                field: "template_folder",
+                reason: format!("folder \"{}\" does not exist", template_folder),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_folder(field: &'static str, folder: &str, create: bool) -> Result<(), ConfigError> {
+    let path = Path::new(folder);
+
+    if !path.exists() {
+        if !create {
+            return Err(ConfigError::Invalid {
+                field,
+                reason: format!(
+                    "folder \"{}\" does not exist (set \"create_folders\" to create it automatically)",
+                    folder
+                ),
+            });
+        }
+
+        fs::create_dir_all(path).map_err(|e| ConfigError::Invalid {
+            field,
+            reason: format!("could not create folder \"{}\": {}", folder, e),
+        })?;
+    } else if !path.is_dir() {
+        return Err(ConfigError::Invalid {
+            field,
+            reason: format!("\"{}\" is not a folder", folder),
+        });
+    }
+
+    Ok(())
+}
+
+pub fn get_folder_size(folder_path: &Path) -> u64 {
+    let mut total_size: u64 = 0;
+
+    for entry in walkdir::WalkDir::new(folder_path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if entry.file_type().is_file() {
+            total_size += entry.metadata().map_or(0, |m| m.len());
+        }
+    }
+
+    total_size
+}
+
+pub fn convert_to_mb(bytes: u64) -> f64 {
+    (bytes as f64 / (1024.0 * 1024.0) * 100.0).round() / 100.0
+}
+
+/// Makes `dst` mirror `src`, linking or copying only files which are missing or differ in size or
+/// modification time, and removing everything that is no longer present in `src`
+pub fn sync_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+
+    if src.is_dir() {
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let src_path = entry.path();
+            let dst_path = dst.join(entry.file_name());
+
+            if entry.file_type()?.is_dir() {
+                sync_dir(&src_path, &dst_path)?;
+                continue;
+            }
+
+            let src_metadata = entry.metadata()?;
+            let is_unchanged = fs::metadata(&dst_path).is_ok_and(|dst_metadata| {
+                dst_metadata.is_file()
+                    && dst_metadata.len() == src_metadata.len()
+                    && dst_metadata.modified().ok() == src_metadata.modified().ok()
+            });
+            if is_unchanged {
+                continue;
+            }
+
+            if dst_path.is_dir() {
+                fs::remove_dir_all(&dst_path)?;
+            } else if dst_path.exists() {
+                fs::remove_file(&dst_path)?;
+            }
+            link_or_copy_file(&src_path, &dst_path)?;
+
+            // Copies get a new modification time, keep the original one so that they aren't copied again
+            if let Ok(modified) = src_metadata.modified() {
+                if fs::metadata(&dst_path)?.modified()? != modified {
+                    File::options()
+                        .write(true)
+                        .open(&dst_path)?
+                        .set_modified(modified)?;
+                }
+            }
+        }
+    }
+
+    for entry in fs::read_dir(dst)? {
+        let entry = entry?;
+        if !src.join(entry.file_name()).exists() {
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn link_or_copy_file(src: &Path, dst: &Path) -> io::Result<()> {
+    // Hard links only work within the same filesystem and may be forbidden for files of other users,
+    // try a copy-on-write clone where the filesystem supports it before falling back to a regular copy
+    if fs::hard_link(src, dst).is_err() {
+        reflink_copy::reflink_or_copy(src, dst)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_file_hash(file_path: &Path) -> io::Result<String> {
+    let mut file = File::open(file_path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+pub fn get_perceptual_hash(file_path: &Path) -> Result<u64, image::ImageError> {
+    // Difference hash: compare neighbouring pixels of a 9x8 grayscale thumbnail,
+    // which survives recompression and resizing of the same picture
+    let thumbnail = image::open(file_path)?
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .into_luma8();
+
+    let mut hash: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = thumbnail.get_pixel(x, y)[0];
+            let right = thumbnail.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | (left < right) as u64;
+        }
+    }
+
+    Ok(hash)
+}
+
+pub fn truncate_string(s: &str, max_length: usize) -> String {
+    if s.chars().count() > max_length {
+        s.chars().take(max_length).collect::<String>() + "..."
+    } else {
+        s.to_string()
+    }
+}
+
+/// Extensions of media files which don't get any smaller when compressed again
+const COMPRESSED_EXTENSIONS: [&str; 8] =
+    ["jpg", "jpeg", "png", "gif", "webp", "mp4", "webm", "zip"];
+
+fn is_compressed_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            COMPRESSED_EXTENSIONS
+                .iter()
+                .any(|compressed| extension.eq_ignore_ascii_case(compressed))
+        })
+}
+
+/// Reader which fails once `cancelled` is set, so that copying a large file stops midway
+struct CancellableReader<'a, R> {
+    inner: R,
+    cancelled: &'a AtomicBool,
+}
+
+impl<R: Read> Read for CancellableReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check_cancelled(self.cancelled)?;
+        self.inner.read(buf)
+    }
+}
+
+fn check_cancelled(cancelled: &AtomicBool) -> io::Result<()> {
+    if cancelled.load(Ordering::Relaxed) {
+        // Not `Interrupted`, which `io::copy` would retry
+        return Err(io::Error::other("job cancelled"));
+    }
+    Ok(())
+}
+
+/// Creates a zip file from pairs of source paths and names inside the archive.
+/// Stops with an error as soon as `cancelled` is set
+pub fn zip_files(
+    files: &[(PathBuf, String)],
+    result_file: &Path,
+    compression: Compression,
+    cancelled: &AtomicBool,
+) -> ZipResult<()> {
+    // Create a zip file
+    let file = File::create(result_file)?;
+    let mut zip = zip::ZipWriter::new(io::BufWriter::new(file));
+
+    for (file_path, name) in files {
+        check_cancelled(cancelled)?;
+
+        let method = if is_compressed_file(file_path) {
+            CompressionMethod::Stored
+        } else {
+            compression.method()
+        };
+        let options = FileOptions::default()
+            .compression_method(method)
+            .large_file(fs::metadata(file_path)?.len() >= u32::MAX as u64);
+
+        // Add each file to the zip archive, copying it in chunks to keep memory usage low
+        zip.start_file(name.as_str(), options)?;
+        let mut file = CancellableReader {
+            inner: File::open(file_path)?,
+            cancelled,
+        };
+        io::copy(&mut file, &mut zip)?;
+    }
+
+    zip.finish()?.flush()?;
+
+    Ok(())
+}
+
+/// Prefix of temporary folders created in `result_folder` for each generation job
+const WORKSPACE_PREFIX: &str = "job-";
+
+/// Creates a unique folder for a single generation job, which is deleted when the returned handle is dropped
+pub fn create_workspace(result_folder: &str, user_id: u64) -> io::Result<TempDir> {
+    tempfile::Builder::new()
+        .prefix(&format!("{}{}-", WORKSPACE_PREFIX, user_id))
+        .tempdir_in(result_folder)
+}
+
+/// Removes workspaces left behind by jobs that were interrupted by a crash or restart
+pub fn remove_stale_workspaces(result_folder: &str) -> io::Result<()> {
+    for entry in fs::read_dir(result_folder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir()
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(WORKSPACE_PREFIX)
+        {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+
+    Ok(())
+}